@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+/// Path extensions and mime types of non-image assets which can be downloaded.
+const ASSET_TYPES: &[(&str, &str)] = &[
+    (".mp4", "video/mp4"),
+    (".m4v", "video/mp4"),
+    (".mov", "video/quicktime"),
+    (".webm", "video/webm"),
+    (".ogv", "application/ogg"),
+    (".mp3", "audio/mpeg"),
+    (".m4a", "audio/mp4"),
+    (".ogg", "application/ogg"),
+    (".oga", "application/ogg"),
+    (".wav", "audio/wav"),
+    (".flac", "audio/flac"),
+    (".pdf", "application/pdf"),
+    (".zip", "application/zip"),
+    (".woff", "font/woff"),
+    (".woff2", "font/woff2"),
+    (".ttf", "font/ttf"),
+    (".otf", "font/otf"),
+];
+
+/// A non-image asset type listed in `--asset-types`, parsed from its extension like `mp4`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssetType {
+    /// Path extension with dot.
+    pub ext: &'static str,
+    pub mime: &'static str,
+}
+
+impl FromStr for AssetType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ext = format!(".{}", s.trim().trim_start_matches('.').to_ascii_lowercase());
+        ASSET_TYPES
+            .iter()
+            .find(|(e, _)| *e == ext)
+            .map(|&(ext, mime)| Self { ext, mime })
+            .ok_or_else(|| format!("unsupported asset type {}", s))
+    }
+}
+
+/// Whether the path extension with dot is of a known asset type, listed or not.
+pub fn is_asset_ext(ext: &str) -> bool {
+    let ext = ext.to_ascii_lowercase();
+    ASSET_TYPES.iter().any(|(e, _)| *e == ext)
+}
+
+/// Detect asset mime type by content, None is returned if it is not a known one.
+pub fn sniff_asset_mime(content: &[u8]) -> Option<&'static str> {
+    let starts = |magic: &[u8]| content.starts_with(magic);
+    if starts(b"%PDF-") {
+        Some("application/pdf")
+    } else if content.get(4..8) == Some(b"ftyp") {
+        match content.get(8..12) {
+            Some(b"qt  ") => Some("video/quicktime"),
+            Some(b"M4A " | b"M4B ") => Some("audio/mp4"),
+            // still images in the same container are detected as images first
+            _ => Some("video/mp4"),
+        }
+    } else if starts(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("video/webm")
+    } else if starts(b"OggS") {
+        Some("application/ogg")
+    } else if starts(b"ID3") || matches!(content, [0xFF, b, ..] if b & 0xE0 == 0xE0) {
+        Some("audio/mpeg")
+    } else if starts(b"RIFF") && content.get(8..12) == Some(b"WAVE") {
+        Some("audio/wav")
+    } else if starts(b"fLaC") {
+        Some("audio/flac")
+    } else if starts(b"PK\x03\x04") {
+        Some("application/zip")
+    } else if starts(b"wOFF") {
+        Some("font/woff")
+    } else if starts(b"wOF2") {
+        Some("font/woff2")
+    } else if starts(&[0, 1, 0, 0]) || starts(b"true") {
+        Some("font/ttf")
+    } else if starts(b"OTTO") {
+        Some("font/otf")
+    } else {
+        None
+    }
+}
+
+/// Pattern of links to assets starting with `start`, captured in group `asset` for plain
+/// markdown links with one of the extensions, or in group `src` for `src` attributes of
+/// `<video>`, `<audio>` and `<source>` tags.
+pub fn asset_pattern(start: &str, types: &[AssetType]) -> String {
+    let exts = types
+        .iter()
+        .map(|t| regex::escape(&t.ext[1..]))
+        .collect::<Vec<_>>()
+        .join("|");
+    format!(
+        r#"\]\(\s*(?P<asset>{start}[^\s()<>]*\.(?i:{exts})(?:[?#][^\s()<>]*)?)(?:\s+"[^"]*")?\s*\)|<(?i:video|audio|source)(?:\s[^>]*?)?\ssrc\s*=\s*["'](?P<src>{start}[^"'\s<>]*)["']"#,
+        start = start,
+        exts = exts
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_asset_type() {
+        use super::{sniff_asset_mime, AssetType};
+
+        let mp4: AssetType = "MP4".parse().unwrap();
+        assert_eq!(mp4.ext, ".mp4");
+        assert_eq!(".pdf".parse::<AssetType>().unwrap().mime, "application/pdf");
+        assert!("exe".parse::<AssetType>().is_err());
+
+        assert_eq!(sniff_asset_mime(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(
+            sniff_asset_mime(b"\0\0\0\x20ftypisom\0\0\x02\0"),
+            Some("video/mp4")
+        );
+        assert_eq!(sniff_asset_mime(b"<!doctype html>"), None);
+    }
+}
@@ -0,0 +1,131 @@
+use std::{collections::HashSet, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::{
+    downloader::{find_markdown, ProcessError},
+    regexp::RegexWrapper,
+    utils::local_file_name,
+    Opts,
+};
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditFormat {
+    Table,
+    Json,
+}
+
+/// Images found in a markdown file.
+#[derive(Serialize, Debug, Default)]
+pub struct FileAudit {
+    pub path: PathBuf,
+    /// Remote images still present.
+    pub remote: Vec<String>,
+    /// Number of local images.
+    pub local: usize,
+    /// Local images pointing at missing files.
+    pub missing: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Audit {
+    pub files: Vec<FileAudit>,
+}
+
+impl Audit {
+    pub fn is_clean(&self) -> bool {
+        self.files
+            .iter()
+            .all(|f| f.remote.is_empty() && f.missing.is_empty())
+    }
+
+    pub fn print(&self, format: AuditFormat) -> Result<(), serde_json::Error> {
+        match format {
+            AuditFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+            AuditFormat::Table => self.print_table(),
+        }
+        Ok(())
+    }
+
+    fn print_table(&self) {
+        let width = self
+            .files
+            .iter()
+            .map(|f| f.path.to_string_lossy().len())
+            .chain(std::iter::once("FILE".len()))
+            .max()
+            .unwrap_or_default();
+        println!("{:<width$}  REMOTE  LOCAL  MISSING", "FILE", width = width);
+        let (mut remote, mut local, mut missing) = (0, 0, 0);
+        for f in self.files.iter() {
+            println!(
+                "{:<width$}  {:>6}  {:>5}  {:>7}",
+                f.path.display(),
+                f.remote.len(),
+                f.local,
+                f.missing.len(),
+                width = width
+            );
+            remote += f.remote.len();
+            local += f.local;
+            missing += f.missing.len();
+        }
+        println!(
+            "{:<width$}  {:>6}  {:>5}  {:>7}",
+            "TOTAL",
+            remote,
+            local,
+            missing,
+            width = width
+        );
+
+        for f in self.files.iter() {
+            for url in f.remote.iter() {
+                println!("remote   {}  {}", f.path.display(), url);
+            }
+            for link in f.missing.iter() {
+                println!("missing  {}  {}", f.path.display(), link);
+            }
+        }
+    }
+}
+
+/// Scan markdown for remote images and local images, missing local images are
+/// checked only if verify_local is set. Nothing is downloaded or written.
+pub fn audit_markdown(opts: &Opts, verify_local: bool) -> Result<Audit, ProcessError> {
+    let file_list = find_markdown(opts, opts.changed_since.as_deref())?;
+    let remote_regex = RegexWrapper::from_opts(opts);
+    let local_regex = RegexWrapper::with_prefix(&opts.link_prefix);
+
+    let mut audit = Audit::default();
+    for path in file_list {
+        let content = std::fs::read_to_string(&path)?;
+
+        let mut remote = HashSet::new();
+        remote_regex.collect_urls(&content, &mut remote);
+        let mut local = HashSet::new();
+        local_regex.collect_urls(&content, &mut local);
+
+        let mut file = FileAudit {
+            remote: remote.into_iter().collect(),
+            local: local.len(),
+            ..Default::default()
+        };
+        if verify_local {
+            file.missing = local
+                .into_iter()
+                .filter(|link| {
+                    let file_name = local_file_name(link, &opts.link_prefix).unwrap_or_default();
+                    !std::path::Path::new(&opts.output_dir)
+                        .join(file_name)
+                        .is_file()
+                })
+                .collect();
+        }
+        file.remote.sort();
+        file.missing.sort();
+        file.path = path;
+        audit.files.push(file);
+    }
+    Ok(audit)
+}
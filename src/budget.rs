@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Downloads and bytes allowed in a run, by `--max-downloads` and `--max-total-bytes`.
+/// Images saved before or taken from cache are not counted.
+pub struct Budget {
+    max_downloads: Option<usize>,
+    max_bytes: Option<u64>,
+    started: AtomicUsize,
+    received: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(max_downloads: Option<usize>, max_bytes: Option<u64>) -> Self {
+        Self {
+            max_downloads,
+            max_bytes,
+            started: AtomicUsize::new(0),
+            received: AtomicU64::new(0),
+        }
+    }
+
+    /// Take a download if any is left. Those in flight when the bytes are used up are
+    /// still finished, so the total may exceed it by them.
+    pub fn start(&self) -> bool {
+        if self
+            .max_bytes
+            .is_some_and(|max| self.received.load(Ordering::Relaxed) >= max)
+        {
+            return false;
+        }
+        match self.max_downloads {
+            Some(max) => self
+                .started
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                    (n < max).then(|| n + 1)
+                })
+                .is_ok(),
+            None => true,
+        }
+    }
+
+    pub fn receive(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_budget() {
+        use super::Budget;
+
+        let budget = Budget::new(Some(2), None);
+        assert!(budget.start() && budget.start());
+        assert!(!budget.start());
+
+        let budget = Budget::new(None, Some(100));
+        assert!(budget.start());
+        budget.receive(60);
+        assert!(budget.start());
+        budget.receive(60);
+        assert!(!budget.start());
+    }
+}
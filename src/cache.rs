@@ -0,0 +1,137 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::utils::write_atomic;
+
+/// Response of a cached url, the content is stored separately by its hash so urls of the
+/// same image share one copy.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CacheEntry {
+    /// Hex SHA-256 of the content.
+    pub sha256: String,
+    /// The url actually downloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl CacheEntry {
+    pub fn new(content: &[u8]) -> Self {
+        Self {
+            sha256: hex::encode(Sha256::digest(content)),
+            source: None,
+            mime: None,
+            etag: None,
+            last_modified: None,
+        }
+    }
+}
+
+/// Downloaded content shared by all runs, laid out as `urls/<sha1 of url>.json` entries and
+/// `objects/<2 chars>/<sha256>` contents.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// `image-downloader` in the user cache dir of the platform.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ if cfg!(windows) => PathBuf::from(std::env::var_os("LOCALAPPDATA")?),
+            _ if cfg!(target_os = "macos") => {
+                Path::new(&std::env::var_os("HOME")?).join("Library/Caches")
+            }
+            _ => Path::new(&std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("image-downloader"))
+    }
+
+    fn entry_path(&self, url: &str) -> PathBuf {
+        let key = sha1::Sha1::from(url.as_bytes()).hexdigest();
+        self.dir.join("urls").join(format!("{}.json", key))
+    }
+
+    fn object_path(&self, sha256: &str) -> PathBuf {
+        self.dir
+            .join("objects")
+            .join(&sha256[..2.min(sha256.len())])
+            .join(sha256)
+    }
+
+    /// Cached response of the url, None if it is missing, unreadable or corrupted.
+    pub fn get(&self, url: &str) -> std::io::Result<Option<(CacheEntry, Bytes)>> {
+        let entry: CacheEntry = match std::fs::read(self.entry_path(url)) {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(entry) => entry,
+                Err(_) => return Ok(None),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let content = match std::fs::read(self.object_path(&entry.sha256)) {
+            Ok(content) => Bytes::from(content),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if hex::encode(Sha256::digest(&content)) != entry.sha256 {
+            return Ok(None);
+        }
+        Ok(Some((entry, content)))
+    }
+
+    /// Cache the response of the url, the content is only written if not cached yet.
+    pub fn put(&self, url: &str, entry: &CacheEntry, content: &[u8]) -> std::io::Result<()> {
+        let object = self.object_path(&entry.sha256);
+        if !object.is_file() {
+            std::fs::create_dir_all(object.parent().expect("object is in a dir"))?;
+            write_atomic(&object, content, false)?;
+        }
+        let path = self.entry_path(url);
+        std::fs::create_dir_all(path.parent().expect("entry is in a dir"))?;
+        let contents = serde_json::to_vec_pretty(entry).expect("entry is always serializable");
+        write_atomic(&path, &contents, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_cache() {
+        use super::{Cache, CacheEntry};
+
+        let dir = std::env::temp_dir().join("image-downloader-cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = Cache::new(dir.clone());
+        assert!(cache.get("https://a.com/a.png").unwrap().is_none());
+
+        let entry = CacheEntry {
+            etag: Some("\"v1\"".to_string()),
+            ..CacheEntry::new(b"png")
+        };
+        cache.put("https://a.com/a.png", &entry, b"png").unwrap();
+        cache.put("https://b.com/a.png", &entry, b"png").unwrap();
+        let (cached, content) = cache.get("https://b.com/a.png").unwrap().unwrap();
+        assert_eq!(cached.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(&content[..], b"png");
+
+        std::fs::write(cache.object_path(&entry.sha256), b"rotten").unwrap();
+        assert!(cache.get("https://a.com/a.png").unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+use crate::{audit::audit_markdown, downloader::ProcessError, CheckOpts, Opts};
+
+/// Scan markdown for remaining remote images(and missing local images if asked)
+/// without downloading or writing anything. Findings are printed to stdout.
+/// Return true if nothing is found.
+pub fn check_markdown(opts: &Opts, check: &CheckOpts) -> Result<bool, ProcessError> {
+    let audit = audit_markdown(opts, check.verify_local)?;
+
+    let (mut remote_count, mut missing_count) = (0, 0);
+    for file in audit.files.iter() {
+        for url in file.remote.iter() {
+            println!("{}: remote image {}", file.path.display(), url);
+        }
+        for link in file.missing.iter() {
+            println!("{}: missing local image {}", file.path.display(), link);
+        }
+        remote_count += file.remote.len();
+        missing_count += file.missing.len();
+    }
+
+    tracing::info!(
+        "checked {} markdown files, found {} remote images and {} missing local images",
+        audit.files.len(),
+        remote_count,
+        missing_count
+    );
+    Ok(audit.is_clean())
+}
@@ -0,0 +1,162 @@
+use std::{collections::BTreeMap, fs::File, io::ErrorKind, path::Path, time::SystemTime};
+
+use sha2::{Digest, Sha256};
+
+use crate::utils::{list_files, write_atomic};
+
+/// SHA-256 of files relative to output dir, read from and written as `sha256sum` output.
+type Checksums = BTreeMap<String, String>;
+
+/// Hex SHA-256 of the file content.
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn read_checksums(path: &Path) -> std::io::Result<Checksums> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let (hash, name) = line.split_once(' ')?;
+            // binary mode of sha256sum marks names with `*`
+            let name = name.trim_start_matches([' ', '*']);
+            Some((name.to_string(), hash.to_ascii_lowercase()))
+        })
+        .collect())
+}
+
+/// Files under output dir except the checksums file itself.
+pub fn output_files(output_dir: &Path, path: &Path) -> std::io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    list_files(output_dir, "", &mut files)?;
+    let own = path
+        .canonicalize()
+        .ok()
+        .and_then(|p| {
+            p.strip_prefix(output_dir.canonicalize().ok()?)
+                .ok()
+                .map(|p| p.to_owned())
+        })
+        .map(|p| p.to_string_lossy().replace('\\', "/"));
+    files.retain(|f| Some(f) != own.as_ref());
+    Ok(files)
+}
+
+/// Update checksums of files in output dir. Recorded files not modified since the last
+/// update keep their checksums, so corruption of them is still detected later. Others are
+/// hashed again, and deleted ones are dropped. Return the number of files hashed.
+pub fn update_checksums(output_dir: &Path, path: &Path) -> std::io::Result<usize> {
+    let (recorded, updated_at) = match std::fs::metadata(path) {
+        Ok(meta) => (read_checksums(path)?, meta.modified()?),
+        Err(e) if e.kind() == ErrorKind::NotFound => (Checksums::new(), SystemTime::UNIX_EPOCH),
+        Err(e) => return Err(e),
+    };
+    let mut checksums = Checksums::new();
+    let mut hashed = 0;
+    for name in output_files(output_dir, path)? {
+        let file = output_dir.join(&name);
+        match recorded.get(&name) {
+            Some(hash) if std::fs::metadata(&file)?.modified()? <= updated_at => {
+                checksums.insert(name, hash.clone());
+            }
+            _ => {
+                checksums.insert(name, hash_file(&file)?);
+                hashed += 1;
+            }
+        }
+    }
+    let contents: String = checksums
+        .iter()
+        .map(|(name, hash)| format!("{}  {}\n", hash, name))
+        .collect();
+    write_atomic(path, contents.as_bytes(), false)?;
+    tracing::info!(
+        "checksums of {} files written to {}, {} hashed",
+        checksums.len(),
+        path.display(),
+        hashed
+    );
+    Ok(hashed)
+}
+
+/// Result of verifying output dir against checksums.
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub verified: usize,
+    pub corrupted: Vec<String>,
+    pub missing: Vec<String>,
+    /// Files not in checksums.
+    pub untracked: Vec<String>,
+}
+
+/// Hash files in output dir again and compare them with checksums.
+pub fn verify_checksums(output_dir: &Path, path: &Path) -> std::io::Result<Verification> {
+    let recorded = read_checksums(path)?;
+    let mut verification = Verification::default();
+    for (name, hash) in recorded.iter() {
+        match hash_file(&output_dir.join(name)) {
+            Ok(actual) if actual == *hash => verification.verified += 1,
+            Ok(_) => verification.corrupted.push(name.clone()),
+            Err(e) if e.kind() == ErrorKind::NotFound => verification.missing.push(name.clone()),
+            Err(e) => return Err(e),
+        }
+    }
+    verification.untracked = output_files(output_dir, path)?
+        .into_iter()
+        .filter(|name| !recorded.contains_key(name))
+        .collect();
+    Ok(verification)
+}
+
+/// Verify output dir and log problems, return whether all recorded files are intact.
+pub fn verify_output(output_dir: &Path, path: &Path) -> std::io::Result<bool> {
+    let verification = verify_checksums(output_dir, path)?;
+    for name in verification.corrupted.iter() {
+        tracing::error!("corrupted: {}", name);
+    }
+    for name in verification.missing.iter() {
+        tracing::error!("missing: {}", name);
+    }
+    for name in verification.untracked.iter() {
+        tracing::debug!("untracked: {}", name);
+    }
+    tracing::info!(
+        "verified {} files, {} corrupted, {} missing, {} untracked",
+        verification.verified,
+        verification.corrupted.len(),
+        verification.missing.len(),
+        verification.untracked.len()
+    );
+    Ok(verification.corrupted.is_empty() && verification.missing.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_checksums() {
+        use super::{update_checksums, verify_checksums};
+
+        let dir = std::env::temp_dir().join("image-downloader-checksums");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("2020")).unwrap();
+        std::fs::write(dir.join("a.png"), b"a").unwrap();
+        std::fs::write(dir.join("2020/b.png"), b"b").unwrap();
+        let sums = dir.join("SHA256SUMS");
+        assert_eq!(update_checksums(&dir, &sums).unwrap(), 2);
+        let contents = std::fs::read_to_string(&sums).unwrap();
+        assert!(contents
+            .contains("ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb  a.png\n"));
+
+        std::fs::write(dir.join("a.png"), b"rotten").unwrap();
+        std::fs::remove_file(dir.join("2020/b.png")).unwrap();
+        std::fs::write(dir.join("c.png"), b"c").unwrap();
+        let verification = verify_checksums(&dir, &sums).unwrap();
+        assert_eq!(verification.verified, 0);
+        assert_eq!(verification.corrupted, ["a.png"]);
+        assert_eq!(verification.missing, ["2020/b.png"]);
+        assert_eq!(verification.untracked, ["c.png"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
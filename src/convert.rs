@@ -0,0 +1,179 @@
+use std::str::FromStr;
+
+use bytes::Bytes;
+
+use crate::utils::get_path_ext;
+
+/// Quality used if not given.
+pub const DEFAULT_QUALITY: f32 = 80.0;
+/// Encoding speed of AVIF used if not given, 1 is the slowest and 10 is the fastest.
+const DEFAULT_SPEED: u8 = 6;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConvertError {
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("webp error: {0}")]
+    WebP(String),
+    #[error("placeholder error: {0}")]
+    Placeholder(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvertFormat {
+    WebP,
+    #[cfg(feature = "avif")]
+    Avif,
+}
+
+/// Re-encode downloaded JPEG and PNG images to another format, parsed from
+/// `format[:quality[:speed]]` like `webp:75` or `avif:60:4`.
+#[derive(Debug, Clone, Copy)]
+pub struct Convert {
+    pub format: ConvertFormat,
+    /// 0 to 100.
+    pub quality: f32,
+    /// 1 to 10, only used by AVIF.
+    #[cfg_attr(not(feature = "avif"), allow(dead_code))]
+    pub speed: u8,
+}
+
+impl FromStr for Convert {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let format = parts.next().unwrap_or_default();
+        let format = match format.to_ascii_lowercase().as_str() {
+            "webp" => ConvertFormat::WebP,
+            #[cfg(feature = "avif")]
+            "avif" => ConvertFormat::Avif,
+            #[cfg(not(feature = "avif"))]
+            "avif" => return Err("avif requires building with feature avif".to_string()),
+            _ => return Err(format!("unsupported format {}", format)),
+        };
+        let quality = match parts.next() {
+            Some(q) => q
+                .parse::<f32>()
+                .ok()
+                .filter(|q| (0.0..=100.0).contains(q))
+                .ok_or_else(|| format!("invalid quality {}", q))?,
+            None => DEFAULT_QUALITY,
+        };
+        let speed = match parts.next() {
+            Some(s) => s
+                .parse::<u8>()
+                .ok()
+                .filter(|s| (1..=10).contains(s))
+                .ok_or_else(|| format!("invalid speed {}", s))?,
+            None => DEFAULT_SPEED,
+        };
+        if parts.next().is_some() {
+            return Err(format!("invalid format {}", s));
+        }
+        Ok(Self {
+            format,
+            quality,
+            speed,
+        })
+    }
+}
+
+impl Convert {
+    /// Path extension with dot of the converted image.
+    pub fn ext(&self) -> &'static str {
+        match self.format {
+            ConvertFormat::WebP => ".webp",
+            #[cfg(feature = "avif")]
+            ConvertFormat::Avif => ".avif",
+        }
+    }
+
+    /// Whether the file will be converted judging by its extension.
+    pub fn accepts(&self, file_name: &str) -> bool {
+        matches!(
+            get_path_ext(file_name)
+                .map(|ext| ext.to_ascii_lowercase())
+                .as_deref(),
+            Some(".png" | ".jpg" | ".jpeg")
+        )
+    }
+
+    /// File name of the converted image.
+    pub fn rename(&self, file_name: &str) -> String {
+        let stem = match get_path_ext(file_name) {
+            Some(ext) => &file_name[..file_name.len() - ext.len()],
+            None => file_name,
+        };
+        format!("{}{}", stem, self.ext())
+    }
+
+    /// Decode the image and encode it in target format. It is slow, so call it
+    /// in blocking threads.
+    pub fn encode(&self, content: &[u8]) -> Result<Bytes, ConvertError> {
+        let image = image::load_from_memory(content)?;
+        match self.format {
+            ConvertFormat::WebP => {
+                let rgba = image.to_rgba8();
+                let encoder = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height());
+                let encoded = encoder
+                    .encode_simple(false, self.quality)
+                    .map_err(|e| ConvertError::WebP(format!("{:?}", e)))?;
+                Ok(Bytes::copy_from_slice(&encoded))
+            }
+            #[cfg(feature = "avif")]
+            ConvertFormat::Avif => {
+                let mut encoded = Vec::new();
+                let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                    &mut encoded,
+                    self.speed,
+                    self.quality as u8,
+                );
+                image.to_rgba8().write_with_encoder(encoder)?;
+                Ok(encoded.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_convert_webp() {
+        use super::{Convert, ConvertFormat};
+        use image::{ImageFormat, RgbImage};
+
+        let convert: Convert = "webp:75".parse().unwrap();
+        assert_eq!(convert.format, ConvertFormat::WebP);
+        assert!("webp:101".parse::<Convert>().is_err());
+        assert!(convert.accepts("a.JPG"));
+        assert!(!convert.accepts("a.gif"));
+        assert_eq!(convert.rename("a.png"), "a.webp");
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbImage::new(8, 8)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let webp = convert.encode(png.get_ref()).unwrap();
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(&webp[8..12], b"WEBP");
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn test_convert_avif() {
+        use super::Convert;
+        use image::{ImageFormat, RgbImage};
+
+        let convert: Convert = "avif:60:10".parse().unwrap();
+        assert_eq!(convert.speed, 10);
+        assert_eq!(convert.rename("a.jpg"), "a.avif");
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbImage::new(8, 8)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let avif = convert.encode(png.get_ref()).unwrap();
+        assert_eq!(&avif[4..8], b"ftyp");
+    }
+}
@@ -0,0 +1,367 @@
+use std::{ops::Range, path::Path};
+
+/// Format of structured data files, scanned as text so they are rewritten in place with
+/// formatting and comments kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// Extensions of data files found in `--data-dir`.
+pub const DATA_EXTS: &[&str] = &["json", "yaml", "yml", "toml"];
+
+impl DataFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+
+    /// String values in contents with their key paths.
+    pub fn scan(self, contents: &str) -> Vec<DataValue> {
+        match self {
+            Self::Json => scan_json(contents),
+            Self::Yaml => scan_yaml(contents),
+            Self::Toml => scan_toml(contents),
+        }
+    }
+}
+
+/// A string value in a data file. Keys leading to it are joined with `/` in path, array
+/// items add no key, so `gallery: [{src: ...}]` gives `gallery/src`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DataValue {
+    pub path: String,
+    /// Byte range of the value as written, without quotes.
+    pub range: Range<usize>,
+}
+
+/// Position of the closing quote of the string starting after `start`, skipping escaped
+/// quotes if escapes are allowed.
+fn string_end(bytes: &[u8], start: usize, quote: u8, escapes: bool) -> Option<usize> {
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if escapes => i += 2,
+            b'\n' if quote != b'"' || !escapes => return None,
+            c if c == quote => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Values with escapes are skipped, as their text differs from the value.
+fn push_value(values: &mut Vec<DataValue>, contents: &str, path: String, range: Range<usize>) {
+    if !range.is_empty() && !contents[range.clone()].contains('\\') {
+        values.push(DataValue { path, range });
+    }
+}
+
+fn scan_json(contents: &str) -> Vec<DataValue> {
+    let bytes = contents.as_bytes();
+    let mut values = Vec::new();
+    // enclosing containers, with the current key if it is an object
+    let mut stack: Vec<(bool, Option<String>)> = Vec::new();
+    let mut expect_key = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'{' => {
+                stack.push((true, None));
+                expect_key = true;
+            }
+            b'[' => stack.push((false, None)),
+            b'}' | b']' => {
+                stack.pop();
+            }
+            b',' => expect_key = stack.last().is_some_and(|(object, _)| *object),
+            b':' => expect_key = false,
+            b'"' => {
+                let end = match string_end(bytes, i + 1, b'"', true) {
+                    Some(end) => end,
+                    None => break,
+                };
+                let text = &contents[i + 1..end];
+                match stack.last_mut() {
+                    Some((true, key)) if expect_key => *key = Some(text.to_string()),
+                    _ => {
+                        let path = stack
+                            .iter()
+                            .filter_map(|(_, key)| key.as_deref())
+                            .collect::<Vec<_>>()
+                            .join("/");
+                        push_value(&mut values, contents, path, i + 1..end);
+                    }
+                }
+                i = end;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    values
+}
+
+/// Lines with their byte offsets in contents.
+fn lines_with_offsets(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents.split_inclusive('\n').scan(0, |offset, line| {
+        let start = *offset;
+        *offset += line.len();
+        Some((start, line.trim_end_matches(['\n', '\r'])))
+    })
+}
+
+/// Ranges of quoted strings in the line after from, offsets are in the line.
+fn quoted_strings(line: &str, from: usize) -> Vec<Range<usize>> {
+    let bytes = line.as_bytes();
+    let mut ranges = Vec::new();
+    let mut i = from;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'#' => break,
+            quote @ (b'"' | b'\'') => match string_end(bytes, i + 1, quote, quote == b'"') {
+                Some(end) => {
+                    ranges.push(i + 1..end);
+                    i = end;
+                }
+                None => break,
+            },
+            _ => (),
+        }
+        i += 1;
+    }
+    ranges
+}
+
+/// Unquote a key of TOML or YAML.
+fn unquote(key: &str) -> &str {
+    let key = key.trim();
+    key.strip_prefix(['"', '\''])
+        .and_then(|k| k.strip_suffix(['"', '\'']))
+        .unwrap_or(key)
+}
+
+fn scan_toml(contents: &str) -> Vec<DataValue> {
+    let mut values = Vec::new();
+    let mut table: Vec<String> = Vec::new();
+    // key of a multi-line array and its bracket depth
+    let mut array: Option<(String, i32)> = None;
+    for (offset, line) in lines_with_offsets(contents) {
+        let trimmed = line.trim_start();
+        if let Some((path, depth)) = &mut array {
+            for range in quoted_strings(line, 0) {
+                push_value(
+                    &mut values,
+                    contents,
+                    path.clone(),
+                    offset + range.start..offset + range.end,
+                );
+            }
+            *depth += bracket_depth(line);
+            if *depth <= 0 {
+                array = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with('[') {
+            let header = trimmed.trim_start_matches('[');
+            let header = header.split(']').next().unwrap_or_default();
+            table = header.split('.').map(|k| unquote(k).to_string()).collect();
+            continue;
+        }
+        let (key, value_at) = match line.find('=') {
+            Some(pos) if !trimmed.starts_with('#') => (&line[..pos], pos + 1),
+            _ => continue,
+        };
+        let path = table
+            .iter()
+            .map(String::as_str)
+            .chain(key.split('.').map(unquote))
+            .collect::<Vec<_>>()
+            .join("/");
+        for range in quoted_strings(line, value_at) {
+            push_value(
+                &mut values,
+                contents,
+                path.clone(),
+                offset + range.start..offset + range.end,
+            );
+        }
+        let depth = bracket_depth(&line[value_at..]);
+        if depth > 0 {
+            array = Some((path, depth));
+        }
+    }
+    values
+}
+
+/// Opened minus closed brackets outside strings.
+fn bracket_depth(line: &str) -> i32 {
+    let strings = quoted_strings(line, 0);
+    line.char_indices()
+        .filter(|(i, _)| !strings.iter().any(|r| r.start <= *i && *i < r.end))
+        .map(|(_, c)| match c {
+            '[' => 1,
+            ']' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+fn scan_yaml(contents: &str) -> Vec<DataValue> {
+    let mut values = Vec::new();
+    // indents and keys of enclosing mappings
+    let mut stack: Vec<(usize, String)> = Vec::new();
+    // lines indented deeper than this are in a block scalar
+    let mut block_indent: Option<usize> = None;
+    for (offset, line) in lines_with_offsets(contents) {
+        let content = line.trim_start();
+        let mut indent = line.len() - content.len();
+        if content.is_empty() || content.starts_with('#') || content.starts_with("---") {
+            continue;
+        }
+        match block_indent {
+            Some(block) if indent > block => continue,
+            _ => block_indent = None,
+        }
+        // each `- ` of sequence items moves the content right
+        let mut content = content;
+        while let Some(rest) = content
+            .strip_prefix("- ")
+            .or(content.strip_prefix('-').filter(|r| r.is_empty()))
+        {
+            let trimmed = rest.trim_start();
+            indent += content.len() - trimmed.len();
+            content = trimmed;
+        }
+        while stack.last().is_some_and(|(i, _)| *i >= indent) {
+            stack.pop();
+        }
+        let value_at = offset + line.len() - content.len();
+        let (key, value) = match yaml_key(content) {
+            Some((key, value_pos)) => (Some(key), value_pos),
+            None => (None, 0),
+        };
+        let rest = &content[value..];
+        let path = stack
+            .iter()
+            .map(|(_, k)| k.as_str())
+            .chain(key)
+            .collect::<Vec<_>>()
+            .join("/");
+        let scalar = rest.trim_start();
+        let scalar_at = value_at + value + rest.len() - scalar.len();
+        if scalar.is_empty() {
+            if let Some(key) = key {
+                stack.push((indent, key.to_string()));
+            }
+        } else if scalar.starts_with(['|', '>']) {
+            block_indent = Some(indent);
+        } else if scalar.starts_with(['"', '\'']) {
+            if let Some(range) = quoted_strings(scalar, 0).into_iter().next() {
+                push_value(
+                    &mut values,
+                    contents,
+                    path,
+                    scalar_at + range.start..scalar_at + range.end,
+                );
+            }
+        } else if scalar.starts_with('[') {
+            for range in quoted_strings(scalar, 0) {
+                push_value(
+                    &mut values,
+                    contents,
+                    path.clone(),
+                    scalar_at + range.start..scalar_at + range.end,
+                );
+            }
+        } else {
+            // plain scalars end before a comment
+            let end = scalar.find(" #").unwrap_or(scalar.len());
+            let plain = scalar[..end].trim_end();
+            push_value(
+                &mut values,
+                contents,
+                path,
+                scalar_at..scalar_at + plain.len(),
+            );
+        }
+    }
+    values
+}
+
+/// Key of a mapping entry and where its value starts, urls like `https://a` are not keys
+/// as the colon is not followed by a space.
+fn yaml_key(content: &str) -> Option<(&str, usize)> {
+    let pos = match content.find(": ") {
+        Some(pos) => pos,
+        None => content.strip_suffix(':').map(str::len)?,
+    };
+    let key = &content[..pos];
+    if key.contains(['{', '[', '#']) || (key.contains(' ') && !key.starts_with(['"', '\''])) {
+        return None;
+    }
+    Some((unquote(key), pos + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_scan_data() {
+        use super::DataFormat;
+
+        let scan = |format: DataFormat, contents: &str| -> Vec<(String, String)> {
+            format
+                .scan(contents)
+                .into_iter()
+                .map(|v| (v.path, contents[v.range].to_string()))
+                .collect()
+        };
+        let pair = |path: &str, value: &str| (path.to_string(), value.to_string());
+
+        let yaml = "title: Gallery # home\n\
+            gallery:\n  \
+              - src: https://a.com/1.png\n    \
+                alt: \"one\"\n  \
+              - src: 'https://a.com/2.png' # second\n\
+            cover: https://a.com/c.jpg\n\
+            note: |\n  \
+              src: https://a.com/no.png\n";
+        assert_eq!(
+            scan(DataFormat::Yaml, yaml),
+            [
+                pair("title", "Gallery"),
+                pair("gallery/src", "https://a.com/1.png"),
+                pair("gallery/alt", "one"),
+                pair("gallery/src", "https://a.com/2.png"),
+                pair("cover", "https://a.com/c.jpg"),
+            ]
+        );
+
+        let toml = "cover = \"https://a.com/c.jpg\"\n\
+            [[gallery]]\n\
+            src = 'https://a.com/1.png'\n\
+            images = [\n  \"https://a.com/2.png\",\n]\n";
+        assert_eq!(
+            scan(DataFormat::Toml, toml),
+            [
+                pair("cover", "https://a.com/c.jpg"),
+                pair("gallery/src", "https://a.com/1.png"),
+                pair("gallery/images", "https://a.com/2.png"),
+            ]
+        );
+
+        let json = r#"{"gallery": [{"src": "https://a.com/1.png", "n": 1}], "a\"b": "x\"y"}"#;
+        assert_eq!(
+            scan(DataFormat::Json, json),
+            [pair("gallery/src", "https://a.com/1.png")]
+        );
+    }
+}
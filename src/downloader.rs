@@ -1,16 +1,58 @@
 use std::{
     collections::{HashMap, HashSet},
-    io::Write,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use bytes::Bytes;
-use reqwest::{Client, StatusCode};
-use tokio::sync::Semaphore;
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use reqwest::{
+    header::{HeaderValue, USER_AGENT},
+    redirect::Policy,
+    Client, ClientBuilder, RequestBuilder, StatusCode,
+};
+use tokio::{sync::Semaphore, task::JoinHandle};
 
-use crate::{regexp::RegexWrapper, utils::get_path_ext, Opts};
+use crate::{
+    asset::{sniff_asset_mime, AssetType},
+    budget::Budget,
+    cache::{Cache, CacheEntry},
+    checksums::update_checksums,
+    convert::{Convert, DEFAULT_QUALITY},
+    data::{DataFormat, DATA_EXTS},
+    git::{changed_files, GitError},
+    hook::Hook,
+    interactive::confirm_sources,
+    manifest::{ImageAttrs, Manifest},
+    metadata::strip_metadata,
+    optimize,
+    placeholder::{placeholder, PlaceholderKind},
+    profile::{Profile, ProfileError, Profiles},
+    progress::Progress,
+    provenance::{sidecar_name, Provenance},
+    regexp::RegexWrapper,
+    report::{read_failures, read_mapping, write_mapping, ImageReport, Outcome, Report},
+    resolve::{private_host, DohResolver, PublicResolver},
+    robots::Robots,
+    shutdown::Shutdown,
+    srcset::{dimensions, Srcset},
+    storage::{build_storage, Storage, StorageError, StorageKind},
+    svg::sanitize_svg,
+    template::resolve_subdir,
+    throttle::{Limiter, Throttle},
+    utils::{
+        display_url, ext_to_mime, get_path_ext, is_decodable, known_path_ext, local_file_name,
+        mime_to_ext, relative_link, sniff_mime, split_data_uri, unwrap_proxy, write_atomic,
+        IMAGE_EXTS,
+    },
+    Opts,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ProcessError {
@@ -18,55 +60,798 @@ pub enum ProcessError {
     Glob(#[from] glob::GlobError),
     #[error("io error")]
     IO(#[from] std::io::Error),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("git error: {0}")]
+    Git(#[from] GitError),
+    #[error("watch error: {0}")]
+    Watch(#[from] notify::Error),
+    #[error("profile error: {0}")]
+    Profile(#[from] ProfileError),
+    #[error("server error: {0}")]
+    Server(#[from] hyper::Error),
+    #[error("{0} downloaded images are still referenced")]
+    Referenced(usize),
 }
 
 /// Process markdown, downlaod and replace.
-pub async fn process_markdown(opts: Opts) -> Result<(), ProcessError> {
-    // find files
-    let options = glob::MatchOptions {
-        case_sensitive: false,
-        ..Default::default()
+pub async fn process_markdown(opts: Opts, shutdown: Shutdown) -> Result<Report, ProcessError> {
+    let storage = build_storage(opts.storage, &opts)?;
+    let report = match &opts.retry_failed {
+        // only the failed urls and their source files are processed
+        Some(path) => {
+            let sources = read_failures(path)?;
+            let mut file_list: Vec<_> = sources.values().flatten().cloned().collect();
+            file_list.sort();
+            file_list.dedup();
+            tracing::info!("retrying {} failed urls", sources.len());
+            let scanned = ScannedContents::default();
+            process_sources(&opts, storage, &file_list, sources, scanned, shutdown).await?
+        }
+        None => {
+            let file_list = find_markdown(&opts, opts.changed_since.as_deref())?;
+            process_files(&opts, storage, &file_list, shutdown).await?
+        }
+    };
+    write_report(&opts, &report, false)?;
+    Ok(report)
+}
+
+/// Rewrite markdown with the mapping given or recorded in manifest, nothing is downloaded.
+pub fn process_mapping(opts: Opts) -> Result<Report, ProcessError> {
+    let start = Instant::now();
+    let mapping = match &opts.mapping {
+        Some(path) => read_mapping(path)?,
+        None => Manifest::load(&opts.manifest)?.mapping(),
+    };
+    let file_list = find_markdown(&opts, opts.changed_since.as_deref())?;
+    tracing::info!(
+        "rewriting {} markdown files with {} mapped urls",
+        file_list.len(),
+        mapping.len()
+    );
+    let mut regex = RegexWrapper::from_opts(&opts);
+    // urls not in mapping are expected to stay, contents are kept to be rewritten
+    let mut urls = HashSet::new();
+    let mut linked = Vec::new();
+    let mut scanned = ScannedContents::default();
+    for path in file_list.iter() {
+        let modified = std::fs::metadata(path)?.modified()?;
+        let contents = std::fs::read_to_string(path)?;
+        let mut set = HashSet::new();
+        match DataFormat::from_path(path) {
+            Some(format) => regex.collect_data_urls(format, &contents, &mut set),
+            None => regex.collect_urls(&contents, &mut set),
+        }
+        if !set.is_empty() {
+            urls.extend(set);
+            linked.push(path);
+            scanned.keep(path.clone(), modified, contents);
+        }
+    }
+    let unmapped: HashSet<String> = urls
+        .into_iter()
+        .filter(|url| !mapping.contains_key(url))
+        .collect();
+    if !unmapped.is_empty() {
+        tracing::warn!("{} urls are not in mapping and kept", unmapped.len());
+    }
+    regex.leave_untouched(unmapped);
+    let rewrite_opts = RewriteOpts::from(&opts);
+    for path in linked {
+        let contents = match scanned.take(path) {
+            Some(scanned) => scanned.into_current(path)?,
+            None => std::fs::read_to_string(path)?,
+        };
+        rewrite_file(
+            path,
+            contents,
+            &regex,
+            &mapping,
+            &HashMap::new(),
+            &rewrite_opts,
+        )?;
+    }
+    tracing::info!("rewritten all markdown files done");
+    let report = Report {
+        files: file_list.len(),
+        images: Vec::new(),
+        duration_ms: start.elapsed().as_millis() as u64,
     };
-    let path = Path::new(&opts.input).join("**/*.md");
+    write_report(&opts, &report, false)?;
+    Ok(report)
+}
+
+/// Write failure log and report if asked. If stdout is taken, report is only written to file.
+fn write_report(opts: &Opts, report: &Report, stdout_taken: bool) -> Result<(), ProcessError> {
+    if let Some(path) = &opts.failure_log {
+        report.write_failures(path)?;
+    }
+    match (opts.report, &opts.report_file) {
+        (Some(_), None) if stdout_taken => {
+            tracing::warn!("report is skipped without --report-file, stdout is taken")
+        }
+        (Some(format), path) => report.emit(format, path.as_ref())?,
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Download images in a markdown document from stdin and write the rewritten one to stdout.
+pub async fn process_stdin(opts: Opts, shutdown: Shutdown) -> Result<Report, ProcessError> {
+    let mut contents = String::new();
+    std::io::stdin().read_to_string(&mut contents)?;
+    let storage = build_storage(opts.storage, &opts)?;
+    let (contents, report) = process_document(&opts, storage, contents, shutdown).await?;
+    std::io::stdout().write_all(contents.as_bytes())?;
+    write_report(&opts, &report, true)?;
+    Ok(report)
+}
+
+/// Download images in a markdown document and return the rewritten one.
+pub(crate) async fn process_document(
+    opts: &Opts,
+    storage: Arc<dyn Storage>,
+    contents: String,
+    shutdown: Shutdown,
+) -> Result<(String, Report), ProcessError> {
+    let regex = RegexWrapper::from_opts(opts);
+    let mut urls = HashSet::new();
+    regex.collect_urls(&contents, &mut urls);
+    let sources = urls.into_iter().map(|url| (url, Vec::new())).collect();
+    let scanned = ScannedContents::default();
+    let report = process_sources(opts, storage, &[], sources, scanned, shutdown).await?;
+
+    let mapping = report
+        .images
+        .iter()
+        .filter_map(|image| Some((image.url.clone(), image.link.clone()?)))
+        .collect();
+    let attrs = report
+        .images
+        .iter()
+        .filter(|image| !image.attrs.is_empty())
+        .map(|image| (image.url.clone(), image.attrs.clone()))
+        .collect();
+    let rewrite_opts = RewriteOpts::from(opts);
+    let contents = rewrite_opts.replace(&contents, &regex, &mapping, &attrs);
+    Ok((contents, report))
+}
 
+/// Download urls listed in the file without scanning markdown, and print the url to link
+/// mapping tab separated. Empty lines and lines starting with `#` are skipped.
+pub async fn process_url_list(
+    opts: Opts,
+    path: &Path,
+    shutdown: Shutdown,
+) -> Result<Report, ProcessError> {
+    let regex = RegexWrapper::from_opts(&opts);
+    let sources: Sources = std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|url| Some((regex.key(url)?, Vec::new())))
+        .collect();
+    tracing::info!("read {} urls from {}", sources.len(), path.display());
+    let storage = build_storage(opts.storage, &opts)?;
+    let scanned = ScannedContents::default();
+    let report = process_sources(&opts, storage, &[], sources, scanned, shutdown).await?;
+
+    let mut stdout = std::io::stdout().lock();
+    let mut images: Vec<_> = report.images.iter().collect();
+    images.sort_by(|a, b| a.url.cmp(&b.url));
+    for image in images {
+        if let Some(link) = &image.link {
+            writeln!(stdout, "{}\t{}", image.url, link)?;
+        }
+    }
+    write_report(&opts, &report, true)?;
+    Ok(report)
+}
+
+/// Download images in the given markdown files and replace.
+pub(crate) async fn process_files(
+    opts: &Opts,
+    storage: Arc<dyn Storage>,
+    file_list: &[PathBuf],
+    shutdown: Shutdown,
+) -> Result<Report, ProcessError> {
     // collect urls
-    let mut set = HashSet::new();
+    let regex = RegexWrapper::from_opts(opts);
+    let (sources, scanned) = scan_markdown(file_list, &regex, opts.file_limit).await?;
+    process_sources(opts, storage, file_list, sources, scanned, shutdown).await
+}
+
+/// Download the collected urls and replace them in markdown files.
+async fn process_sources(
+    opts: &Opts,
+    storage: Arc<dyn Storage>,
+    file_list: &[PathBuf],
+    mut sources: Sources,
+    mut scanned: ScannedContents,
+    shutdown: Shutdown,
+) -> Result<Report, ProcessError> {
+    let start = Instant::now();
+    let mut regex = RegexWrapper::from_opts(opts);
+    let rewrite_opts = Arc::new(RewriteOpts::from(opts));
+    let mut manifest = Manifest::load(&opts.manifest)?;
+    // urls expected to stay, denied or deferred ones
+    let mut untouched = HashSet::new();
+    if opts.interactive {
+        let stdin = std::io::stdin();
+        let approved = confirm_sources(sources.clone(), &mut stdin.lock(), &mut std::io::stderr())?;
+        // denied ones stay in files rewritten for approved ones
+        untouched.extend(
+            sources
+                .into_keys()
+                .filter(|url| !approved.contains_key(url)),
+        );
+        regex.leave_untouched(untouched.clone());
+        sources = approved;
+    }
+
+    // urls not finished yet in each file, none are rewritten without rewriting
+    let mut pending: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+    for (url, files) in sources.iter().filter(|_| !opts.no_rewrite) {
+        for file in files {
+            pending.entry(file.clone()).or_default().insert(url.clone());
+        }
+    }
+    // urls in each file, so only their mapping is copied for rewriting
+    let file_urls: HashMap<PathBuf, Vec<String>> = pending
+        .iter()
+        .map(|(file, urls)| (file.clone(), urls.iter().cloned().collect()))
+        .collect();
+
+    // download them, and recorded ones if refreshing or forced
+    storage.prepare().await?;
+    let mut downloader = Downloader::new(opts, storage)?;
+    let mut urls: HashSet<String> = sources.keys().cloned().collect();
+    if opts.refresh || opts.force {
+        let recorded: HashMap<_, _> = manifest
+            .images
+            .iter()
+            .filter(|(url, _)| !url.starts_with("data:"))
+            .map(|(url, entry)| (url.clone(), entry.attrs.clone()))
+            .collect();
+        urls.extend(recorded.keys().cloned());
+        if opts.refresh {
+            downloader.refresh = Some(recorded);
+        }
+    }
+    // images are saved in the sub directory of the first file they are found in
+    if let Some(template) = &opts.link_template {
+        let mut resolved: HashMap<&Path, String> = HashMap::new();
+        for (url, files) in sources.iter() {
+            let file = match files.first() {
+                Some(file) => file.as_path(),
+                None => continue,
+            };
+            let subdir = match resolved.get(file) {
+                Some(subdir) => subdir.clone(),
+                None => {
+                    let subdir = match scanned.get(file) {
+                        Some(contents) => resolve_subdir(template, &opts.input, file, contents),
+                        None => {
+                            let contents = std::fs::read_to_string(file)?;
+                            resolve_subdir(template, &opts.input, file, &contents)
+                        }
+                    };
+                    resolved.insert(file, subdir.clone());
+                    subdir
+                }
+            };
+            downloader.subdirs.insert(url.clone(), subdir);
+        }
+    }
+    let progress = Progress::new(urls.len() as u64, opts.quiet == 0 && !opts.no_progress);
+    let downloads = download_images(
+        urls,
+        Arc::new(downloader),
+        opts.current_limit,
+        progress.clone(),
+        shutdown,
+        Duration::from_secs(opts.grace_sec),
+    );
+    futures::pin_mut!(downloads);
+
+    // replace them back as soon as all urls in a file are finished
+    let file_semaphore = Arc::new(Semaphore::new(opts.file_limit));
+    let mut rewrites = Vec::new();
+    let mut result_mapping = HashMap::new();
+    let mut attrs = HashMap::new();
+    let mut regex = Arc::new(regex);
+    let mut images = Vec::with_capacity(sources.len());
+    let mut untouched_changed = false;
+    while let Some(mut image) = downloads.next().await {
+        if let Some(link) = &image.link {
+            result_mapping.insert(image.url.clone(), link.clone());
+        }
+        if image.outcome == Outcome::Deferred {
+            untouched_changed |= untouched.insert(image.url.clone());
+        }
+        // attributes of skipped images are generated by a previous run
+        if image.outcome == Outcome::Cached {
+            if let Some(entry) = manifest.images.get(&image.url) {
+                image.attrs = entry.attrs.clone();
+            }
+        }
+        if !image.attrs.is_empty() {
+            attrs.insert(image.url.clone(), image.attrs.clone());
+        }
+        image.files = sources.remove(&image.url).unwrap_or_default();
+        for file in image.files.iter().filter(|_| !opts.no_rewrite) {
+            let urls = pending.get_mut(file).expect("file must be pending");
+            urls.remove(&image.url);
+            if urls.is_empty() {
+                pending.remove(file);
+                if std::mem::take(&mut untouched_changed) {
+                    Arc::make_mut(&mut regex).leave_untouched(untouched.clone());
+                }
+                let (mapping, attrs) = file_snapshot(&file_urls[file], &result_mapping, &attrs);
+                rewrites.push(spawn_rewrite(
+                    file.clone(),
+                    scanned.take(file),
+                    regex.clone(),
+                    mapping,
+                    attrs,
+                    rewrite_opts.clone(),
+                    file_semaphore.clone(),
+                ));
+            }
+        }
+        images.push(image);
+    }
+    progress.finish();
+    tracing::info!("downloaded {} images", result_mapping.len());
+
+    // persist mapping, so it is kept even if rewriting fails
+    manifest.update(&result_mapping, &attrs);
+    manifest.save(&opts.manifest)?;
+    if opts.placeholder.is_some() {
+        let content = serde_json::to_vec_pretty(&manifest.placeholders())?;
+        write_atomic(&opts.placeholder_file, &content, false)?;
+    }
+    if let Some(path) = &opts.dimensions_file {
+        let content = serde_json::to_vec_pretty(&manifest.dimensions())?;
+        write_atomic(path, &content, false)?;
+    }
+    if let Some(path) = &opts.export_mapping {
+        write_mapping(path, &result_mapping)?;
+    }
+    if let Some(path) = &opts.checksums {
+        match opts.storage {
+            StorageKind::Local | StorageKind::GitLfs => {
+                update_checksums(opts.output_dir.as_ref(), path)?;
+            }
+            _ => tracing::warn!("checksums are only written for local and git-lfs storages"),
+        }
+    }
+
+    // files with unfinished urls(interrupted) are rewritten with what we have
+    if untouched_changed {
+        Arc::make_mut(&mut regex).leave_untouched(untouched);
+    }
+    for file in pending.into_keys() {
+        let contents = scanned.take(&file);
+        let (mapping, attrs) = file_snapshot(&file_urls[&file], &result_mapping, &attrs);
+        rewrites.push(spawn_rewrite(
+            file,
+            contents,
+            regex.clone(),
+            mapping,
+            attrs,
+            rewrite_opts.clone(),
+            file_semaphore.clone(),
+        ));
+    }
+    for rewrite in rewrites {
+        rewrite.await.expect("rewrite task panicked")?;
+    }
+    if opts.no_rewrite {
+        tracing::info!("markdown files are left untouched");
+    } else {
+        tracing::info!("rewritten all markdown files done");
+    }
+
+    let report = Report {
+        files: file_list.len(),
+        images,
+        duration_ms: start.elapsed().as_millis() as u64,
+    };
+    report.log_summary();
+    Ok(report)
+}
+
+/// Mapping and attributes of the given urls, copied for rewriting the file they are in.
+fn file_snapshot(
+    urls: &[String],
+    mapping: &HashMap<String, String>,
+    attrs: &HashMap<String, ImageAttrs>,
+) -> (HashMap<String, String>, HashMap<String, ImageAttrs>) {
+    let mapping = urls
+        .iter()
+        .filter_map(|url| Some((url.clone(), mapping.get(url)?.clone())))
+        .collect();
+    let attrs = urls
+        .iter()
+        .filter_map(|url| Some((url.clone(), attrs.get(url)?.clone())))
+        .collect();
+    (mapping, attrs)
+}
+
+/// Rewrite a single markdown file in blocking thread with bounded parallelism.
+fn spawn_rewrite(
+    path: PathBuf,
+    scanned: Option<Scanned>,
+    regex: Arc<RegexWrapper>,
+    mapping: HashMap<String, String>,
+    attrs: HashMap<String, ImageAttrs>,
+    rewrite_opts: Arc<RewriteOpts>,
+    semaphore: Arc<Semaphore>,
+) -> JoinHandle<Result<(), ProcessError>> {
+    tokio::spawn(async move {
+        let _permit = semaphore
+            .acquire_owned()
+            .await
+            .expect("unable to acquire semaphore");
+        tokio::task::spawn_blocking(move || {
+            let contents = match scanned {
+                Some(scanned) => scanned.into_current(&path)?,
+                None => std::fs::read_to_string(&path)?,
+            };
+            rewrite_file(&path, contents, &regex, &mapping, &attrs, &rewrite_opts)?;
+            Ok(())
+        })
+        .await
+        .expect("rewrite task panicked")
+    })
+}
+
+/// Find markdown files under inputs matched by include and exclude patterns.
+/// If changed_since is given, only files changed since the git ref are returned.
+pub(crate) fn find_markdown(
+    opts: &Opts,
+    changed_since: Option<&str>,
+) -> Result<Vec<PathBuf>, ProcessError> {
     let mut file_list = Vec::new();
-    let regex = RegexWrapper::new();
-    for entry in glob::glob_with(&path.to_string_lossy(), options).expect("invalid glob pattern") {
-        let path = entry?;
-        let content = std::fs::read_to_string(&path)?;
-        regex.collect_urls(content, &mut set);
-        file_list.push(path);
+    for input in opts.input.iter().map(Path::new) {
+        if let Some(git_ref) = changed_since {
+            let changed = changed_files(input, git_ref)?;
+            file_list.extend(changed.into_iter().filter(|p| is_included(opts, p)));
+            continue;
+        }
+        for pattern in opts.include.iter() {
+            let path = input.join(pattern.as_str());
+            for entry in glob::glob_with(&path.to_string_lossy(), PATTERN_OPTIONS)
+                .expect("invalid glob pattern")
+            {
+                let entry = entry?;
+                if entry.is_file() && is_included(opts, &entry) {
+                    file_list.push(entry);
+                }
+            }
+        }
+    }
+    // data files are not filtered by include and exclude patterns of markdown
+    for dir in opts.data_dir.iter().map(Path::new) {
+        let is_data = |p: &PathBuf| DataFormat::from_path(p).is_some();
+        if let Some(git_ref) = changed_since {
+            file_list.extend(changed_files(dir, git_ref)?.into_iter().filter(is_data));
+            continue;
+        }
+        for ext in DATA_EXTS {
+            let path = dir.join(format!("**/*.{}", ext));
+            for entry in glob::glob_with(&path.to_string_lossy(), PATTERN_OPTIONS)
+                .expect("invalid glob pattern")
+            {
+                let entry = entry?;
+                if entry.is_file() {
+                    file_list.push(entry);
+                }
+            }
+        }
+    }
+    // patterns and inputs may overlap
+    file_list.sort();
+    file_list.dedup();
+    if let Some(git_ref) = changed_since {
+        tracing::info!(
+            "found {} markdown files changed since {}",
+            file_list.len(),
+            git_ref
+        );
+    }
+    Ok(file_list)
+}
+
+const PATTERN_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Whether the file under an input is matched by include patterns and not by exclude ones.
+pub(crate) fn is_included(opts: &Opts, path: &Path) -> bool {
+    let relative = opts
+        .input
+        .iter()
+        .find_map(|input| path.strip_prefix(input).ok())
+        .unwrap_or(path);
+    let matches = |p: &glob::Pattern| p.matches_path_with(relative, PATTERN_OPTIONS);
+    opts.include.iter().any(matches) && !opts.exclude.iter().any(matches)
+}
+
+/// Links and the markdown files they are found in.
+pub(crate) type Sources = HashMap<String, Vec<PathBuf>>;
+
+/// Total size of scanned contents kept for rewriting, files read after it is reached are
+/// read again when rewritten.
+const KEPT_CONTENTS_LIMIT: usize = 256 << 20;
+
+/// Contents of a markdown file read when scanning, with its modified time before reading.
+pub(crate) struct Scanned {
+    modified: SystemTime,
+    contents: String,
+}
+
+impl Scanned {
+    /// Contents as scanned, or read again if the file is changed since.
+    fn into_current(self, path: &Path) -> std::io::Result<String> {
+        match std::fs::metadata(path)?.modified()? == self.modified {
+            true => Ok(self.contents),
+            false => std::fs::read_to_string(path),
+        }
+    }
+}
+
+/// Scanned contents of markdown files with links, so each file is read once.
+#[derive(Default)]
+pub(crate) struct ScannedContents {
+    files: HashMap<PathBuf, Scanned>,
+    size: usize,
+}
+
+impl ScannedContents {
+    fn keep(&mut self, path: PathBuf, modified: SystemTime, contents: String) {
+        if self.size + contents.len() <= KEPT_CONTENTS_LIMIT {
+            self.size += contents.len();
+            self.files.insert(path, Scanned { modified, contents });
+        }
     }
+
+    fn get(&self, path: &Path) -> Option<&str> {
+        self.files.get(path).map(|s| s.contents.as_str())
+    }
+
+    /// Take the contents out once the file is to be rewritten.
+    fn take(&mut self, path: &Path) -> Option<Scanned> {
+        self.files.remove(path)
+    }
+}
+
+/// Collect the links matched by regex in markdown files, file_limit files are read in parallel.
+/// Contents of files with links are kept to be rewritten without reading again.
+pub(crate) async fn scan_markdown(
+    file_list: &[PathBuf],
+    regex: &RegexWrapper,
+    file_limit: usize,
+) -> Result<(Sources, ScannedContents), ProcessError> {
+    let mut sources = Sources::new();
+    let mut scanned = ScannedContents::default();
+    // owned paths keep the future Send for callers spawning it, like the server
+    let mut reads = futures::stream::iter(file_list.to_vec())
+        .map(|path| async move {
+            let read = async {
+                let modified = tokio::fs::metadata(&path).await?.modified()?;
+                Ok::<_, std::io::Error>((modified, tokio::fs::read_to_string(&path).await?))
+            };
+            let read = read.await;
+            (path, read)
+        })
+        .buffer_unordered(file_limit);
+    while let Some((path, read)) = reads.next().await {
+        let (modified, contents) = read?;
+        let mut set = HashSet::new();
+        match DataFormat::from_path(&path) {
+            Some(format) => regex.collect_data_urls(format, &contents, &mut set),
+            None => regex.collect_urls(&contents, &mut set),
+        }
+        if set.is_empty() {
+            continue;
+        }
+        for url in set {
+            sources.entry(url).or_default().push(path.clone());
+        }
+        scanned.keep(path, modified, contents);
+    }
+    sources.values_mut().for_each(|files| files.sort());
     tracing::info!(
         "scanned {} links in {} markdown files",
-        set.len(),
+        sources.len(),
         file_list.len()
     );
+    Ok((sources, scanned))
+}
 
-    // download them
-    let result_mapping = download_images(
-        set,
-        opts.output_dir,
-        opts.link_prefix,
-        Duration::from_secs(opts.timeout_sec as u64),
-        opts.current_limit,
-    )
-    .await;
-    tracing::info!("downloaded {} images", result_mapping.len());
+/// How markdown files are rewritten.
+pub(crate) struct RewriteOpts {
+    preserve_mtime: bool,
+    backup: bool,
+    backup_dir: Option<PathBuf>,
+    /// Images with srcset are rendered with it if set.
+    html_template: Option<String>,
+    /// Link prefix and output dir, links under it are made relative to markdown files.
+    relative_links: Option<(String, PathBuf)>,
+}
+
+impl From<&Opts> for RewriteOpts {
+    fn from(opts: &Opts) -> Self {
+        Self {
+            preserve_mtime: opts.preserve_mtime,
+            backup: opts.backup,
+            backup_dir: opts.backup_dir.clone(),
+            html_template: (!opts.srcset.is_empty()).then(|| opts.srcset_template.clone()),
+            relative_links: (opts.relative_links
+                && matches!(opts.storage, StorageKind::Local | StorageKind::GitLfs))
+            .then(|| (opts.link_prefix.clone(), PathBuf::from(&opts.output_dir))),
+        }
+    }
+}
+
+impl RewriteOpts {
+    /// Replace the links in contents with the mapping.
+    fn replace(
+        &self,
+        contents: &str,
+        regex: &RegexWrapper,
+        mapping: &HashMap<String, String>,
+        attrs: &HashMap<String, ImageAttrs>,
+    ) -> String {
+        match &self.html_template {
+            Some(template) => regex.replace_html(contents, mapping, template, attrs),
+            None => regex.replace_urls(contents, mapping),
+        }
+    }
 
-    // replace them back
+    /// Link prefix and the relative link from the markdown file to output dir, if links
+    /// are made relative.
+    pub(crate) fn relative_base(&self, path: &Path) -> std::io::Result<Option<(&str, String)>> {
+        let (prefix, output_dir) = match &self.relative_links {
+            Some(relative_links) => relative_links,
+            None => return Ok(None),
+        };
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        Ok(Some((prefix, relative_link(dir, output_dir)?)))
+    }
+
+    /// Write the new contents if they are changed, with the original backed up if asked.
+    /// Return whether it is changed.
+    pub(crate) fn write(
+        &self,
+        path: &Path,
+        contents: &str,
+        new_contents: &str,
+    ) -> std::io::Result<bool> {
+        // untouched files are not written, or watchers will see our own changes
+        if new_contents == contents {
+            return Ok(false);
+        }
+        self.backup(path, contents)?;
+        write_atomic(path, new_contents.as_bytes(), self.preserve_mtime)?;
+        Ok(true)
+    }
+
+    /// Keep the original content before it is rewritten.
+    fn backup(&self, path: &Path, contents: &str) -> std::io::Result<()> {
+        if self.backup {
+            let mut bak = path.as_os_str().to_owned();
+            bak.push(".bak");
+            std::fs::write(bak, contents)?;
+        }
+        if let Some(dir) = &self.backup_dir {
+            // mirror the path of markdown file under backup dir
+            let relative: PathBuf = path
+                .components()
+                .filter(|c| matches!(c, std::path::Component::Normal(_)))
+                .collect();
+            let bak = dir.join(relative);
+            if let Some(parent) = bak.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(bak, contents)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replace the links in markdown files with the mapping.
+pub(crate) fn rewrite_markdown(
+    file_list: &[PathBuf],
+    regex: &RegexWrapper,
+    mapping: &HashMap<String, String>,
+    rewrite_opts: &RewriteOpts,
+) -> Result<(), ProcessError> {
     for path in file_list {
-        let contents = std::fs::read_to_string(&path)?;
-        let new_contents = regex.replace_urls(contents, &result_mapping);
-        std::fs::write(&path, new_contents)?;
+        let contents = std::fs::read_to_string(path)?;
+        rewrite_file(
+            path,
+            contents,
+            regex,
+            mapping,
+            &HashMap::new(),
+            rewrite_opts,
+        )?;
     }
     tracing::info!("rewritten all markdown files done");
-
     Ok(())
 }
 
+/// Replace the links in the contents of a markdown file with the mapping, return whether
+/// it is changed.
+pub(crate) fn rewrite_file(
+    path: &Path,
+    contents: String,
+    regex: &RegexWrapper,
+    mapping: &HashMap<String, String>,
+    attrs: &HashMap<String, ImageAttrs>,
+    rewrite_opts: &RewriteOpts,
+) -> std::io::Result<bool> {
+    let relative = rewrite_opts
+        .relative_base(path)?
+        .map(|(prefix, base)| relativize(prefix, &base, mapping, attrs));
+    let (mapping, attrs) = match &relative {
+        Some((mapping, attrs)) => (mapping, attrs),
+        None => (mapping, attrs),
+    };
+    let new_contents = match DataFormat::from_path(path) {
+        Some(format) => regex.replace_data_urls(format, &contents, mapping),
+        None => rewrite_opts.replace(&contents, regex, mapping, attrs),
+    };
+    rewrite_opts.write(path, &contents, &new_contents)
+}
+
+/// Replace link prefix of links with base, including those in srcset.
+pub(crate) fn relativize(
+    prefix: &str,
+    base: &str,
+    mapping: &HashMap<String, String>,
+    attrs: &HashMap<String, ImageAttrs>,
+) -> (HashMap<String, String>, HashMap<String, ImageAttrs>) {
+    let relative = |link: &str| match local_file_name(link, prefix) {
+        Some(file_name) if base.is_empty() => file_name.to_string(),
+        Some(file_name) => format!("{}/{}", base, file_name),
+        None => link.to_string(),
+    };
+    let mapping = mapping
+        .iter()
+        .map(|(url, link)| (url.clone(), relative(link)))
+        .collect();
+    let attrs = attrs
+        .iter()
+        .map(|(url, attrs)| {
+            let mut attrs = attrs.clone();
+            if let Some(srcset) = &attrs.srcset {
+                let candidates: Vec<_> = srcset
+                    .split(", ")
+                    .map(|candidate| match candidate.split_once(' ') {
+                        Some((link, width)) => format!("{} {}", relative(link), width),
+                        None => relative(candidate),
+                    })
+                    .collect();
+                attrs.srcset = Some(candidates.join(", "));
+            }
+            (url.clone(), attrs)
+        })
+        .collect();
+    (mapping, attrs)
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum DownloadError {
     #[error("reqwest error: {0}")]
@@ -75,120 +860,910 @@ pub enum DownloadError {
     InvalidStatusCode(StatusCode),
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
+    #[error("invalid data uri")]
+    InvalidDataUri,
+    #[error("base64 decode error: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("not archived in wayback machine")]
+    NotArchived,
+    #[error("not an image, content type is {0}")]
+    NotImage(String),
+    #[error("content is {1} but extension is of {0}")]
+    MismatchedType(&'static str, &'static str),
+    #[error("svg error: {0}")]
+    Svg(String),
+    #[error("no data received in {0:?}")]
+    ReadTimeout(Duration),
+    #[error("download budget of this run is used up")]
+    Deferred,
+    #[error("not downloaded before, unavailable offline")]
+    Offline,
+    #[error("private address {0} is blocked")]
+    PrivateAddress(IpAddr),
+    #[error("disallowed by robots.txt")]
+    Disallowed,
+}
+
+impl DownloadError {
+    /// Whether the link is dead, the host is gone or the image is not found.
+    fn is_dead_link(&self) -> bool {
+        match self {
+            DownloadError::InvalidStatusCode(status) => {
+                *status == StatusCode::NOT_FOUND || *status == StatusCode::GONE
+            }
+            DownloadError::Reqwest(e) => e.is_connect(),
+            _ => false,
+        }
+    }
+
+    /// Whether it may succeed if tried again.
+    fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::InvalidStatusCode(status) => {
+                *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            DownloadError::Reqwest(e) => e.is_timeout() || e.is_connect() || e.is_body(),
+            DownloadError::ReadTimeout(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Sent if no user agent is given.
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/94.0.4606.81 Safari/537.36";
+
+/// Shared by downloading tasks.
+pub(crate) struct Downloader {
+    client: Client,
+    user_agents: Vec<HeaderValue>,
+    next_user_agent: AtomicUsize,
+    read_timeout: Duration,
+    throttle: Throttle,
+    storage: Arc<dyn Storage>,
+    wayback: bool,
+    convert: Option<Convert>,
+    sanitize_svg: bool,
+    strip_metadata: bool,
+    optimize: bool,
+    srcset: Option<Srcset>,
+    placeholder: Option<PlaceholderKind>,
+    /// Recorded attributes of images to refresh, keyed by url.
+    refresh: Option<HashMap<String, ImageAttrs>>,
+    /// Download again even if saved.
+    force: bool,
+    provenance: bool,
+    /// Sub directories resolved from link prefix template, keyed by url.
+    subdirs: HashMap<String, String>,
+    profiles: Profiles,
+    asset_types: Vec<AssetType>,
+    robots: Option<Robots>,
+    cache: Option<Cache>,
+    /// Make no requests, images not saved before are only taken from cache.
+    offline: bool,
+    budget: Budget,
+    block_private_ips: bool,
+    hook: Option<Hook>,
+    /// None for remote storages, whose files are only known by their keys.
+    output_dir: Option<PathBuf>,
+}
+
+impl Downloader {
+    pub(crate) fn new(opts: &Opts, storage: Arc<dyn Storage>) -> Result<Self, ProfileError> {
+        let user_agents = user_agents(opts);
+        let builder = || client_builder(opts, &user_agents[0]);
+        let client = match &opts.client {
+            Some(client) => client.clone(),
+            None => builder().build().expect("unable to build reqwest client"),
+        };
+        let robots = opts.respect_robots.then(|| {
+            let user_agent = user_agents[0].to_str().unwrap_or_default().to_string();
+            Robots::new(client.clone(), user_agent)
+        });
+        let cache_dir = match &opts.cache_dir {
+            Some(dir) => Some(dir.clone()),
+            None if opts.cache || opts.offline => {
+                let dir = Cache::default_dir();
+                if dir.is_none() {
+                    tracing::warn!("no user cache dir found, cache is disabled");
+                }
+                dir
+            }
+            None => None,
+        };
+        let profiles = match &opts.profiles {
+            Some(path) => Profiles::load(path, &builder)?,
+            None => Profiles::default(),
+        };
+        Ok(Self {
+            client,
+            user_agents,
+            next_user_agent: AtomicUsize::new(0),
+            read_timeout: Duration::from_secs(opts.read_timeout as u64),
+            throttle: Throttle::new(opts.max_bandwidth, opts.max_host_bandwidth),
+            storage,
+            wayback: opts.wayback,
+            convert: opts.convert,
+            sanitize_svg: opts.sanitize_svg,
+            strip_metadata: opts.strip_metadata,
+            optimize: opts.optimize,
+            srcset: (!opts.srcset.is_empty()).then(|| {
+                let quality = opts.convert.map_or(DEFAULT_QUALITY, |c| c.quality);
+                Srcset::new(opts.srcset.clone(), quality)
+            }),
+            placeholder: opts.placeholder,
+            refresh: None,
+            force: opts.force,
+            provenance: opts.provenance,
+            subdirs: HashMap::new(),
+            profiles,
+            asset_types: opts.asset_types.clone(),
+            robots,
+            cache: cache_dir.map(Cache::new),
+            offline: opts.offline,
+            block_private_ips: opts.block_private_ips,
+            budget: Budget::new(opts.max_downloads, opts.max_total_bytes.map(|b| b.0)),
+            hook: match (&opts.hook, opts.storage) {
+                (Some(command), StorageKind::Local | StorageKind::GitLfs) => {
+                    Some(Hook::new(command.clone(), opts.hook_limit.max(1)))
+                }
+                (Some(_), _) => {
+                    tracing::warn!("hooks are only run for local and git-lfs storages");
+                    None
+                }
+                (None, _) => None,
+            },
+            output_dir: matches!(opts.storage, StorageKind::Local | StorageKind::GitLfs)
+                .then(|| PathBuf::from(&opts.output_dir)),
+        })
+    }
+}
+
+/// Valid user agents given, or the default one.
+fn user_agents(opts: &Opts) -> Vec<HeaderValue> {
+    let mut user_agents: Vec<HeaderValue> = opts
+        .user_agent
+        .iter()
+        .filter_map(|ua| match HeaderValue::from_str(ua) {
+            Ok(ua) => Some(ua),
+            Err(_) => {
+                tracing::warn!("ignoring invalid user agent {}", ua);
+                None
+            }
+        })
+        .collect();
+    if user_agents.is_empty() {
+        user_agents.push(HeaderValue::from_static(DEFAULT_USER_AGENT));
+    }
+    user_agents
+}
+
+/// Client built from options, long-running modes keep one in `Opts::client` so connections
+/// are reused across runs.
+pub(crate) fn build_client(opts: &Opts) -> Client {
+    client_builder(opts, &user_agents(opts)[0])
+        .build()
+        .expect("unable to build reqwest client")
+}
+
+/// Client settings shared by all requests.
+fn client_builder(opts: &Opts, user_agent: &HeaderValue) -> ClientBuilder {
+    let mut builder = Client::builder()
+        .user_agent(user_agent.clone())
+        .timeout(Duration::from_secs(opts.timeout_sec as u64))
+        .connect_timeout(Duration::from_secs(opts.connect_timeout as u64))
+        .pool_idle_timeout(Duration::from_secs(opts.pool_idle_timeout as u64))
+        .tcp_keepalive(opts.tcp_keepalive.map(|s| Duration::from_secs(s as u64)))
+        .http2_adaptive_window(opts.http2_adaptive_window);
+    #[cfg(feature = "http3")]
+    if opts.http3 {
+        builder = builder.http3_prior_knowledge();
+    }
+    if let Some(max) = opts.pool_max_idle {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    let doh = opts.doh.as_ref().map(|endpoint| {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(opts.timeout_sec as u64))
+            .connect_timeout(Duration::from_secs(opts.connect_timeout as u64))
+            .build()
+            .expect("unable to build reqwest client");
+        DohResolver::new(endpoint.clone(), client)
+    });
+    if opts.block_private_ips {
+        // redirects to addresses are checked here, and those to hosts when resolved
+        builder = builder
+            .dns_resolver(Arc::new(PublicResolver::new(doh)))
+            .redirect(Policy::custom(|attempt| {
+                match private_host(attempt.url()) {
+                    Some(ip) => attempt.error(format!("redirected to private address {}", ip)),
+                    None => Policy::default().redirect(attempt),
+                }
+            }));
+    } else if let Some(doh) = doh {
+        builder = builder.dns_resolver(Arc::new(doh));
+    }
+    // binding to an unspecified address of a family skips addresses of the other
+    if opts.ipv4 {
+        builder = builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    } else if opts.ipv6 {
+        builder = builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+    }
+    for entry in opts.resolve.iter() {
+        builder = builder.resolve(&entry.host, entry.socket_addr());
+    }
+    builder
 }
 
-/// Download images to output folder and return the result of new url.
-/// You may make sure the output_dir already exists.
-async fn download_images(
+/// Download images to storage with at most current_limit in parallel, and yield the result
+/// of each url once it is finished. No more downloads are started once interrupted, and
+/// in-flight ones are cancelled if they are not finished in grace period.
+fn download_images(
     urls: HashSet<String>,
-    output_dir: String,
-    prefix: String,
-    timeout: Duration,
+    downloader: Arc<Downloader>,
     current_limit: u32,
-) -> HashMap<String, String> {
-    let semaphore = Arc::new(Semaphore::new(current_limit as usize));
-    let client = Client::builder()
-        .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/94.0.4606.81 Safari/537.36")
-        .timeout(timeout)
-        .build()
-        .expect("unable to build reqwest client");
-    let mut join_handles = Vec::with_capacity(urls.len());
-    let results = Arc::new(Mutex::new(HashMap::with_capacity(urls.len())));
+    progress: Progress,
+    shutdown: Shutdown,
+    grace: Duration,
+) -> impl Stream<Item = ImageReport> {
+    let mut interrupted = shutdown.clone();
+    let mut expired = shutdown;
+    let grace_deadline = async move {
+        expired.triggered().await;
+        tokio::time::sleep(grace).await;
+        tracing::warn!("grace period expired, cancelling in-flight downloads");
+    };
+    futures::stream::iter(urls)
+        .take_until(async move { interrupted.triggered().await })
+        .map(move |url| {
+            let (downloader, progress) = (downloader.clone(), progress.clone());
+            async move { downloader.download_image(url, &progress).await }
+        })
+        .buffer_unordered(current_limit as usize)
+        .take_until(grace_deadline)
+}
 
-    for url in urls.into_iter() {
-        let permit = semaphore
-            .clone()
-            .acquire_owned()
+/// A downloaded image with the mime type and validators in response.
+#[derive(Default)]
+struct Fetched {
+    content: Bytes,
+    /// The url responded after redirects.
+    source: Option<String>,
+    mime: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// A saved image.
+struct Saved {
+    file_name: String,
+    link: String,
+    attrs: ImageAttrs,
+    bytes: u64,
+    outcome: Outcome,
+}
+
+impl Downloader {
+    /// Download a single image and report the result.
+    async fn download_image(&self, url: String, progress: &Progress) -> ImageReport {
+        let start = Instant::now();
+        let ret = self.download_and_save(&url).await;
+        let mut report = ImageReport {
+            url,
+            outcome: Outcome::Downloaded,
+            error: None,
+            link: None,
+            hook_error: None,
+            path: None,
+            attrs: ImageAttrs::default(),
+            files: Vec::new(),
+            bytes: 0,
+            duration_ms: start.elapsed().as_millis() as u64,
+        };
+        match ret {
+            Ok(saved) => {
+                let path = match &self.output_dir {
+                    Some(dir) => dir.join(&saved.file_name),
+                    None => PathBuf::from(&saved.file_name),
+                };
+                if let (Some(hook), Outcome::Downloaded | Outcome::Refreshed) =
+                    (&self.hook, saved.outcome)
+                {
+                    if let Err(e) = hook.run(&path, &report.url, &saved.link).await {
+                        tracing::warn!("hook of {} failed: {}", display_url(&report.url), e);
+                        report.hook_error = Some(e);
+                    }
+                }
+                progress.success(saved.bytes);
+                report.outcome = saved.outcome;
+                report.link = Some(saved.link);
+                report.path = Some(path);
+                report.attrs = saved.attrs;
+                report.bytes = saved.bytes;
+            }
+            Err(DownloadError::Disallowed) => {
+                tracing::info!("skipping {}, disallowed by robots.txt", report.url);
+                progress.success(0);
+                report.outcome = Outcome::Disallowed;
+            }
+            Err(DownloadError::Deferred) => {
+                tracing::debug!("deferring {} to a later run", display_url(&report.url));
+                progress.success(0);
+                report.outcome = Outcome::Deferred;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "processing image {} with error {}",
+                    display_url(&report.url),
+                    e
+                );
+                progress.fail();
+                report.outcome = Outcome::Failed;
+                report.error = Some(e.to_string());
+            }
+        }
+        report
+    }
+
+    /// Download a single image and save it.
+    async fn download_and_save(&self, url: &str) -> Result<Saved, DownloadError> {
+        // 0. skip if saved by a previous run, unless forced
+        let mut file_name = match self.subdirs.get(url) {
+            Some(subdir) if !subdir.is_empty() => format!("{}/{}", subdir, file_name_for(url)),
+            _ => file_name_for(url),
+        };
+        // converted one is preferred, the original is saved if converting failed
+        let mut saved_names = vec![file_name.clone()];
+        if let Some(convert) = self.convert.filter(|c| c.accepts(&file_name)) {
+            saved_names.insert(0, convert.rename(&file_name));
+        }
+        if self.force {
+            saved_names.clear();
+        }
+        let mut refreshed = None;
+        for saved_name in saved_names {
+            let link = match self.lookup(&saved_name).await? {
+                Some(link) => link,
+                None => continue,
+            };
+            // or download it again if it is changed
+            if let Some(fetched) = self.refetch(url).await {
+                tracing::info!("{} is changed, replacing {}", display_url(url), saved_name);
+                refreshed = Some(fetched);
+                break;
+            }
+            tracing::debug!("skipping {}, {} exists", display_url(url), saved_name);
+            return Ok(Saved {
+                file_name: saved_name,
+                link,
+                attrs: ImageAttrs::default(),
+                bytes: 0,
+                outcome: Outcome::Cached,
+            });
+        }
+        let outcome = match refreshed {
+            Some(_) => Outcome::Refreshed,
+            None => Outcome::Downloaded,
+        };
+
+        // 1. download image, data uri will be decoded directly
+        let fetched = match refreshed {
+            Some(fetched) => fetched,
+            None if url.starts_with("data:") => Fetched {
+                content: decode_data_uri(url)?,
+                mime: split_data_uri(url).map(|(mime, _)| mime.to_string()),
+                ..Default::default()
+            },
+            None => match self.cached(url) {
+                Some(fetched) => fetched,
+                None if self.offline => return Err(DownloadError::Offline),
+                None => {
+                    if !self.budget.start() {
+                        return Err(DownloadError::Deferred);
+                    }
+                    let fetched = self.download_remote(url).await?;
+                    self.budget.receive(fetched.content.len() as u64);
+                    self.cache_fetched(url, &fetched);
+                    fetched
+                }
+            },
+        };
+
+        // 2. check it is an image or allowed asset, hotlink protected hosts may return html
+        let content = fetched.content;
+        let asset = self.sniff_asset(&content);
+        let mime = sniff_mime(&content)
+            .or(asset.map(|a| a.mime))
+            .ok_or_else(|| {
+                DownloadError::NotImage(fetched.mime.as_deref().unwrap_or("unknown").to_string())
+            })?;
+        match get_path_ext(&file_name).and_then(ext_to_mime) {
+            Some(expected) if expected != mime => {
+                // images are often served in other formats than the path says, like webp
+                let ext = mime_to_ext(mime).filter(|_| mime.starts_with("image/"));
+                match (ext, get_path_ext(&file_name)) {
+                    (Some(ext), Some(old)) => {
+                        tracing::warn!("{} is {} rather than {}", display_url(url), mime, expected);
+                        file_name.truncate(file_name.len() - old.len());
+                        file_name.push_str(ext);
+                    }
+                    _ => return Err(DownloadError::MismatchedType(expected, mime)),
+                }
+            }
+            // take extension from content if the path has none
+            None if get_path_ext(&file_name).is_none() => {
+                let ext = mime_to_ext(mime).or(asset.map(|a| a.ext));
+                file_name.push_str(ext.unwrap_or_default());
+            }
+            _ => (),
+        }
+        let content = match mime {
+            "image/svg+xml" if self.sanitize_svg => {
+                sanitize_svg(&content).map_err(|e| DownloadError::Svg(e.to_string()))?
+            }
+            _ => content,
+        };
+        let (file_name, content) = self.convert(file_name, content).await;
+        let content = match self.strip_metadata {
+            true => strip_metadata(&content).unwrap_or(content),
+            false => content,
+        };
+        let content = self.optimize(&file_name, content).await;
+        let bytes = content.len() as u64;
+
+        // 3. save image, and where it comes from if asked
+        let link = self.storage.save(&file_name, content.clone()).await?;
+        if self.provenance {
+            let provenance = Provenance {
+                fetched_from: fetched.source,
+                content_type: fetched.mime,
+                etag: fetched.etag.clone(),
+                last_modified: fetched.last_modified.clone(),
+                ..Provenance::new(url, &content)
+            };
+            let sidecar =
+                serde_json::to_vec_pretty(&provenance).expect("provenance is always serializable");
+            self.storage
+                .save(&sidecar_name(&file_name), sidecar.into())
+                .await?;
+        }
+
+        // 4. save resized variants and generate dimensions and placeholder
+        let mut attrs = ImageAttrs {
+            etag: fetched.etag,
+            last_modified: fetched.last_modified,
+            ..Default::default()
+        };
+        if is_decodable(&file_name) {
+            if let Some((width, height)) = dimensions(&content) {
+                attrs.width = Some(width);
+                attrs.height = Some(height);
+            }
+            if let Some(srcset) = &self.srcset {
+                attrs.srcset = self
+                    .save_variants(srcset.clone(), &file_name, &link, content.clone())
+                    .await?;
+            }
+            if let Some(kind) = self.placeholder {
+                let ret = tokio::task::spawn_blocking(move || placeholder(kind, &content))
+                    .await
+                    .expect("unable to join placeholder task");
+                match ret {
+                    Ok(placeholder) => attrs.placeholder = Some(placeholder),
+                    Err(e) => {
+                        tracing::warn!("generating placeholder of {} with error {}", file_name, e)
+                    }
+                }
+            }
+        }
+        Ok(Saved {
+            file_name,
+            link,
+            attrs,
+            bytes,
+            outcome,
+        })
+    }
+
+    /// Save resized variants and return the srcset, None if the image is narrower than
+    /// all widths or it can not be resized.
+    async fn save_variants(
+        &self,
+        srcset: Srcset,
+        file_name: &str,
+        link: &str,
+        content: Bytes,
+    ) -> Result<Option<String>, DownloadError> {
+        let name = file_name.to_string();
+        let ret = tokio::task::spawn_blocking(move || srcset.variants(&name, &content))
             .await
-            .expect("unable to acquire semaphore");
-        let (client, output_dir, prefix, results) = (
-            client.clone(),
-            output_dir.clone(),
-            prefix.clone(),
-            results.clone(),
-        );
-        let join = tokio::spawn(async move {
-            // 1. download image
-            let ret = download_single(client, &url).await;
-            if let Err(e) = ret {
-                tracing::error!("downloading single image {} with error {}", &url, e);
-                return;
-            }
-            let content = ret.unwrap();
-
-            // 2. save image
-            let save = save_single(&output_dir, &content, &url);
-            if let Err(e) = save {
-                tracing::error!("saving single image {} with error {}", &url, e);
-                return;
-            }
-            let link = PathBuf::from(prefix).join(save.unwrap());
-            let mut results = results.lock().expect("unable to lock results");
-            results.insert(
+            .expect("unable to join resizing task");
+        let (width, variants) = match ret {
+            Ok((_, variants)) if variants.is_empty() => return Ok(None),
+            Ok(ret) => ret,
+            Err(e) => {
+                tracing::warn!("resizing {} with error {}, srcset is skipped", file_name, e);
+                return Ok(None);
+            }
+        };
+        let mut candidates = Vec::with_capacity(variants.len() + 1);
+        for variant in variants {
+            let link = self
+                .storage
+                .save(&variant.file_name, variant.content)
+                .await?;
+            candidates.push(format!("{} {}w", link, variant.width));
+        }
+        candidates.push(format!("{} {}w", link, width));
+        Ok(Some(candidates.join(", ")))
+    }
+
+    /// Convert the image if asked, the original is kept if it fails.
+    async fn convert(&self, file_name: String, content: Bytes) -> (String, Bytes) {
+        let convert = match self.convert {
+            Some(convert) if convert.accepts(&file_name) => convert,
+            _ => return (file_name, content),
+        };
+        let original = content.clone();
+        let ret = tokio::task::spawn_blocking(move || convert.encode(&content))
+            .await
+            .expect("unable to join converting task");
+        match ret {
+            Ok(converted) => (convert.rename(&file_name), converted),
+            Err(e) => {
+                tracing::warn!("converting {} with error {}, keep it as is", file_name, e);
+                (file_name, original)
+            }
+        }
+    }
+
+    /// Optimize the image if asked, the original is kept if it fails.
+    async fn optimize(&self, file_name: &str, content: Bytes) -> Bytes {
+        if !self.optimize || !optimize::accepts(file_name) {
+            return content;
+        }
+        let (name, original) = (file_name.to_string(), content.clone());
+        let ret = tokio::task::spawn_blocking(move || optimize::optimize(&name, &content))
+            .await
+            .expect("unable to join optimizing task");
+        match ret {
+            Ok(Some(optimized)) => {
+                tracing::debug!(
+                    "optimized {} from {} to {} bytes",
+                    file_name,
+                    original.len(),
+                    optimized.len()
+                );
+                optimized
+            }
+            Ok(None) => original,
+            Err(e) => {
+                tracing::warn!("optimizing {} with error {}, keep it as is", file_name, e);
+                original
+            }
+        }
+    }
+
+    /// The allowed asset type of content.
+    fn sniff_asset(&self, content: &[u8]) -> Option<AssetType> {
+        let mime = sniff_asset_mime(content)?;
+        self.asset_types.iter().find(|t| t.mime == mime).copied()
+    }
+
+    /// Lookup the file name in storage. Without an extension, it may be saved with the
+    /// extension of its content type, so all known image extensions and allowed asset ones
+    /// are tried.
+    async fn lookup(&self, file_name: &str) -> Result<Option<String>, DownloadError> {
+        if get_path_ext(file_name).is_some() {
+            return Ok(self.storage.lookup(file_name).await?);
+        }
+        let asset_exts = self.asset_types.iter().map(|t| t.ext);
+        for ext in std::iter::once("")
+            .chain(IMAGE_EXTS.iter().copied())
+            .chain(asset_exts)
+        {
+            let link = self
+                .storage
+                .lookup(&format!("{}{}", file_name, ext))
+                .await?;
+            if link.is_some() {
+                return Ok(link);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Download the original image of proxied urls first, then the url itself,
+    /// and the wayback machine snapshot if enabled.
+    async fn download_remote(&self, url: &str) -> Result<Fetched, DownloadError> {
+        if let Some(origin) = unwrap_proxy(url) {
+            match self.download_single(&origin, None).await {
+                Ok(content) => return Ok(content),
+                Err(e) => tracing::warn!(
+                    "downloading original image {} with error {}, using proxy {}",
+                    origin,
+                    e,
+                    url
+                ),
+            }
+        }
+        match self.download_single(url, None).await {
+            Err(e) if self.wayback && e.is_dead_link() => {
+                tracing::warn!("{} is dead with error {}, trying wayback machine", url, e);
+                self.download_wayback(url).await
+            }
+            ret => ret,
+        }
+    }
+
+    /// Cached response of the url, ignored if forced to download again.
+    fn cached(&self, url: &str) -> Option<Fetched> {
+        let cache = self.cache.as_ref().filter(|_| !self.force)?;
+        match cache.get(url) {
+            Ok(Some((entry, content))) => {
+                tracing::debug!("using cached {}", display_url(url));
+                Some(Fetched {
+                    content,
+                    source: entry.source,
+                    mime: entry.mime,
+                    etag: entry.etag,
+                    last_modified: entry.last_modified,
+                })
+            }
+            Ok(None) => None,
+            Err(e) => {
+                tracing::warn!("reading cache of {} with error {}", display_url(url), e);
+                None
+            }
+        }
+    }
+
+    fn cache_fetched(&self, url: &str, fetched: &Fetched) {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return,
+        };
+        let entry = CacheEntry {
+            source: fetched.source.clone(),
+            mime: fetched.mime.clone(),
+            etag: fetched.etag.clone(),
+            last_modified: fetched.last_modified.clone(),
+            ..CacheEntry::new(&fetched.content)
+        };
+        if let Err(e) = cache.put(url, &entry, &fetched.content) {
+            tracing::warn!("caching {} with error {}", display_url(url), e);
+        }
+    }
+
+    /// Download the image again if refreshing and it is changed since recorded. None is
+    /// returned if it is unchanged or failed, the local copy is kept then.
+    async fn refetch(&self, url: &str) -> Option<Fetched> {
+        let recorded = self.refresh.as_ref()?.get(url)?;
+        match self.download_single(url, Some(recorded)).await {
+            Ok(fetched) => {
+                self.cache_fetched(url, &fetched);
+                Some(fetched)
+            }
+            Err(DownloadError::InvalidStatusCode(StatusCode::NOT_MODIFIED)) => None,
+            Err(e) => {
+                tracing::warn!(
+                    "refreshing {} with error {}, keep the local copy",
+                    display_url(url),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Download a url with settings of its profile, it is conditional if validators of a
+    /// previous response are given.
+    async fn download_single(
+        &self,
+        url: &str,
+        recorded: Option<&ImageAttrs>,
+    ) -> Result<Fetched, DownloadError> {
+        let parsed = reqwest::Url::parse(url).ok();
+        if let Some(ip) = parsed.as_ref().and_then(private_host) {
+            if self.block_private_ips {
+                return Err(DownloadError::PrivateAddress(ip));
+            }
+        }
+        if let (Some(robots), Some(parsed)) = (&self.robots, &parsed) {
+            if !robots.is_allowed(parsed).await {
+                return Err(DownloadError::Disallowed);
+            }
+        }
+        let profile = parsed.and_then(|u| self.profiles.find(u.host_str()?));
+        let _permit = match profile.and_then(|p| p.semaphore.as_ref()) {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .expect("semaphore is never closed"),
+            ),
+            None => None,
+        };
+        let mut retried = 0;
+        loop {
+            let ret = self.fetch(url, recorded, profile).await;
+            let profile = match (&ret, profile) {
+                (Err(e), Some(profile)) if e.is_transient() && retried < profile.retries => profile,
+                _ => return ret,
+            };
+            let delay = profile.retry_delay * 2u32.saturating_pow(retried);
+            tracing::warn!(
+                "downloading {} with error {}, retrying in {:?}",
                 url,
-                link.into_os_string()
-                    .into_string()
-                    .expect("unable to convert string"),
+                ret.err().expect("only failures are retried"),
+                delay
             );
+            tokio::time::sleep(delay).await;
+            retried += 1;
+        }
+    }
 
-            // 3. drop permit
-            drop(permit);
-        });
-        join_handles.push(join);
+    async fn fetch(
+        &self,
+        url: &str,
+        recorded: Option<&ImageAttrs>,
+        profile: Option<&Profile>,
+    ) -> Result<Fetched, DownloadError> {
+        tracing::debug!("downloading {}", url);
+        let mut req = self.get(url, profile);
+        if let Some(etag) = recorded.and_then(|r| r.etag.as_deref()) {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = recorded.and_then(|r| r.last_modified.as_deref()) {
+            req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let ret = self.client.execute(req.build()?).await?;
+        if ret.status() != StatusCode::OK {
+            return Err(DownloadError::InvalidStatusCode(ret.status()));
+        }
+        let header = |name| {
+            ret.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        let mime = header(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.split(';').next().map(|v| v.trim().to_ascii_lowercase()));
+        let etag = header(reqwest::header::ETAG);
+        let last_modified = header(reqwest::header::LAST_MODIFIED);
+        let source = Some(ret.url().to_string());
+        let content = self
+            .read_body(ret, profile.and_then(|p| p.limiter.as_ref()))
+            .await?;
+        Ok(Fetched {
+            content,
+            source,
+            mime,
+            etag,
+            last_modified,
+        })
     }
-    for j in join_handles {
-        let _ = j.await;
+
+    /// Read the body in chunks, so slow but progressing downloads are only limited by the
+    /// total timeout, while stalled ones fail after the read timeout. Bandwidth limits are
+    /// applied per chunk.
+    async fn read_body(
+        &self,
+        mut resp: reqwest::Response,
+        limiter: Option<&Limiter>,
+    ) -> Result<Bytes, DownloadError> {
+        let host = resp.url().host_str().unwrap_or_default().to_string();
+        let mut content = BytesMut::new();
+        loop {
+            match tokio::time::timeout(self.read_timeout, resp.chunk()).await {
+                Ok(Ok(Some(chunk))) => {
+                    content.extend_from_slice(&chunk);
+                    self.throttle.consume(&host, chunk.len(), limiter).await;
+                }
+                Ok(Ok(None)) => return Ok(content.freeze()),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => return Err(DownloadError::ReadTimeout(self.read_timeout)),
+            }
+        }
     }
 
-    Arc::try_unwrap(results)
-        .expect("unable to get arc inner")
-        .into_inner()
-        .expect("unable to get mutex inner")
-}
+    /// Build a GET request, with the next user agent if there are many to rotate among,
+    /// and settings of the profile.
+    fn get(&self, url: &str, profile: Option<&Profile>) -> RequestBuilder {
+        let client = profile
+            .and_then(|p| p.client.as_ref())
+            .unwrap_or(&self.client);
+        let mut req = client.get(url);
+        if self.user_agents.len() > 1 {
+            let next = self.next_user_agent.fetch_add(1, Ordering::Relaxed);
+            req = req.header(USER_AGENT, &self.user_agents[next % self.user_agents.len()]);
+        }
+        if let Some(profile) = profile {
+            req = req.headers(profile.headers.clone());
+            if let Some(timeout) = profile.timeout {
+                req = req.timeout(timeout);
+            }
+        }
+        req
+    }
 
-async fn download_single(client: Client, url: &str) -> Result<Bytes, DownloadError> {
-    tracing::info!("downloading {}", url);
-    let req = client.get(url).build()?;
-    let ret = client.execute(req).await?;
-    if ret.status() != StatusCode::OK {
-        return Err(DownloadError::InvalidStatusCode(ret.status()));
+    /// Download the closest snapshot in Internet Archive.
+    async fn download_wayback(&self, url: &str) -> Result<Fetched, DownloadError> {
+        let ret: serde_json::Value = self
+            .get("https://archive.org/wayback/available", None)
+            .query(&[("url", url)])
+            .send()
+            .await?
+            .bytes()
+            .await
+            .map(|b| serde_json::from_slice(&b).unwrap_or_default())?;
+        let closest = &ret["archived_snapshots"]["closest"];
+        let (snapshot, timestamp) = match (closest["url"].as_str(), closest["timestamp"].as_str()) {
+            (Some(snapshot), Some(timestamp)) if closest["available"].as_bool() == Some(true) => {
+                (snapshot, timestamp)
+            }
+            _ => return Err(DownloadError::NotArchived),
+        };
+        // `id_` after timestamp gives the original bytes instead of the wayback page
+        let raw = snapshot.replacen(timestamp, &format!("{}id_", timestamp), 1);
+        self.download_single(&raw, None).await
     }
-    let content = ret.bytes().await.map_err(Into::into);
-    content
 }
 
-fn save_single(output_dir: &str, content: &Bytes, url: &str) -> Result<String, DownloadError> {
-    tracing::info!("saving {}", url);
+fn decode_data_uri(uri: &str) -> Result<Bytes, DownloadError> {
+    tracing::debug!("decoding {}", display_url(uri));
+    let (_, payload) = split_data_uri(uri).ok_or(DownloadError::InvalidDataUri)?;
+    Ok(base64::decode(payload)?.into())
+}
+
+/// Generate file name by url hash with extension, unknown ones are taken from the content
+/// when it is downloaded.
+fn file_name_for(url: &str) -> String {
     let mut file_name = sha1::Sha1::from(url.as_bytes()).hexdigest();
-    if let Some(ext) = get_path_ext(url) {
+    let ext = match split_data_uri(url) {
+        Some((mime, _)) => mime_to_ext(mime),
+        None => known_path_ext(url),
+    };
+    if let Some(ext) = ext {
         file_name.push_str(ext);
     }
-    let path = Path::new(&output_dir).join(&file_name);
-    let mut f = std::fs::File::create(path)?;
-    f.write_all(content)?;
-    Ok(file_name)
+    file_name
 }
 
 #[cfg(test)]
 mod tests {
     #[tokio::test]
     async fn test_download_images() {
-        use super::download_images;
-        use std::time::Duration;
+        use super::{download_images, Downloader};
+        use crate::{progress::Progress, shutdown::Shutdown, storage::LocalStorage, Opts};
+        use clap::Parser;
+        use futures::StreamExt;
+        use std::{sync::Arc, time::Duration};
 
         let _ = std::fs::create_dir_all("/tmp/images");
-        let ret = download_images(
+        let storage = Arc::new(LocalStorage::new(
+            "/tmp/images",
+            "/images",
+            crate::storage::DedupStrategy::Copy,
+            &[],
+        ));
+        let opts = Opts::parse_from(["image-downloader", "--timeout-sec", "20"]);
+        let ret: std::collections::HashMap<_, _> = download_images(
             [
                 "https://i.v2ex.co/R7yApIA5s.jpeg".to_string(),
                 "https://i.v2ex.co/BU0hPU5qs.jpeg".to_string(),
             ]
             .into_iter()
             .collect(),
-            "/tmp/images".to_string(),
-            "/images".to_string(),
-            Duration::from_secs(20),
+            Arc::new(Downloader::new(&opts, storage).unwrap()),
             20,
+            Progress::new(2, false),
+            Shutdown::never(),
+            Duration::from_secs(10),
         )
+        .filter_map(|r| async move { Some((r.url, r.link?)) })
+        .collect()
         .await;
         assert_eq!(ret.len(), 2);
         assert_eq!(
@@ -203,14 +1778,78 @@ mod tests {
 
     #[allow(unused)]
     async fn test_process_markdown() {
-        use super::{process_markdown, Opts};
-        let opts = Opts {
-            input: "/tmp/mds".to_string(),
-            output_dir: "/tmp/images".to_string(),
-            timeout_sec: 20,
-            current_limit: 50,
-            link_prefix: "/images".to_string(),
-        };
-        assert!(process_markdown(opts).await.is_ok());
+        use super::{process_markdown, Opts, Shutdown};
+        use clap::Parser;
+        let opts = Opts::parse_from([
+            "image-downloader",
+            "--input",
+            "/tmp/mds",
+            "--output-dir",
+            "/tmp/images",
+            "--timeout-sec",
+            "20",
+            "--current-limit",
+            "50",
+            "--link-prefix",
+            "/images",
+        ]);
+        assert!(process_markdown(opts, Shutdown::never()).await.is_ok());
+    }
+
+    #[test]
+    fn test_is_included() {
+        use super::{is_included, Opts};
+        use clap::Parser;
+        use std::path::Path;
+
+        let opts = Opts::parse_from(["image-downloader", "--input", "content"]);
+        assert!(is_included(&opts, Path::new("content/a.md")));
+        assert!(is_included(&opts, Path::new("content/posts/a.MD")));
+        assert!(!is_included(&opts, Path::new("content/a.markdown")));
+
+        let opts = Opts::parse_from([
+            "image-downloader",
+            "--input",
+            "content",
+            "--include",
+            "**/*.md,**/*.markdown",
+            "--exclude",
+            "drafts/**",
+        ]);
+        assert!(is_included(&opts, Path::new("content/a.markdown")));
+        assert!(is_included(&opts, Path::new("content/posts/drafts.md")));
+        assert!(!is_included(&opts, Path::new("content/drafts/a.md")));
+        assert!(!is_included(&opts, Path::new("content/drafts/b/a.md")));
+    }
+
+    #[tokio::test]
+    async fn test_scan_markdown_keeps_contents() {
+        use super::{scan_markdown, RegexWrapper};
+        use std::time::{Duration, SystemTime};
+
+        let dir = std::env::temp_dir().join("image-downloader-scanned");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.md"), dir.join("b.md"));
+        std::fs::write(&a, "![a](https://example.com/a.png)").unwrap();
+        std::fs::write(&b, "no images").unwrap();
+        let file_list = [a.clone(), b.clone()];
+        let (sources, mut scanned) = scan_markdown(&file_list, &RegexWrapper::default(), 2)
+            .await
+            .unwrap();
+        assert_eq!(sources["https://example.com/a.png"], [a.as_path()]);
+        assert!(scanned.take(&b).is_none());
+
+        let kept = scanned.take(&a).unwrap();
+        std::fs::write(&a, "![a](https://example.com/a.png) edited").unwrap();
+        let modified = SystemTime::now() + Duration::from_secs(1);
+        std::fs::File::options()
+            .write(true)
+            .open(&a)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(kept.into_current(&a).unwrap().ends_with("edited"));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,55 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum GitError {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("git {0} failed: {1}")]
+    Command(&'static str, String),
+}
+
+/// Run git in dir and return stdout.
+fn git(dir: &Path, subcommand: &'static str, args: &[&str]) -> Result<String, GitError> {
+    let output = Command::new("git")
+        .arg(subcommand)
+        .args(args)
+        .current_dir(dir)
+        .output()?;
+    if !output.status.success() {
+        return Err(GitError::Command(
+            subcommand,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// List files under dir changed since git_ref, including uncommitted and untracked ones.
+/// Deleted files are excluded and returned paths are joined with dir.
+pub fn changed_files(dir: &Path, git_ref: &str) -> Result<Vec<PathBuf>, GitError> {
+    let diff = git(
+        dir,
+        "diff",
+        &["--name-only", "--relative", "--diff-filter=d", git_ref, "--", "."],
+    )?;
+    let untracked = git(dir, "ls-files", &["--others", "--exclude-standard", "--", "."])?;
+
+    let mut files: Vec<PathBuf> = diff
+        .lines()
+        .chain(untracked.lines())
+        .filter(|l| !l.is_empty())
+        .map(|l| dir.join(l))
+        .collect();
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Whether dir has no uncommitted or untracked changes.
+pub fn is_clean(dir: &Path) -> Result<bool, GitError> {
+    let status = git(dir, "status", &["--porcelain", "--", "."])?;
+    Ok(status.trim().is_empty())
+}
@@ -0,0 +1,125 @@
+use std::{path::Path, process::Stdio, str::FromStr};
+
+use tokio::{process::Command, sync::Semaphore};
+
+/// Command given by `--hook`, split into program and arguments like a shell does with
+/// quotes. `{path}`, `{url}` and `{link}` in arguments are replaced for each image, and no
+/// shell is involved, so urls are never interpreted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookCommand(Vec<String>);
+
+impl FromStr for HookCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut args = Vec::new();
+        let mut arg: Option<String> = None;
+        let mut quote = None;
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some('"'), '\\') => match chars.next() {
+                    Some(next @ ('"' | '\\')) => arg.get_or_insert_with(String::new).push(next),
+                    Some(next) => arg.get_or_insert_with(String::new).extend(['\\', next]),
+                    None => return Err(format!("unfinished escape in {}", s)),
+                },
+                (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+                (None, '"' | '\'') => {
+                    quote = Some(c);
+                    arg.get_or_insert_with(String::new);
+                }
+                (None, c) if c.is_whitespace() => args.extend(arg.take()),
+                (None, c) => arg.get_or_insert_with(String::new).push(c),
+            }
+        }
+        if quote.is_some() {
+            return Err(format!("unclosed quote in {}", s));
+        }
+        args.extend(arg);
+        if args.is_empty() {
+            return Err("hook command is empty".to_string());
+        }
+        Ok(Self(args))
+    }
+}
+
+/// Run the hook command after images are saved, at most limit of them in parallel.
+pub struct Hook {
+    command: HookCommand,
+    semaphore: Semaphore,
+}
+
+impl Hook {
+    pub fn new(command: HookCommand, limit: usize) -> Self {
+        Self {
+            command,
+            semaphore: Semaphore::new(limit),
+        }
+    }
+
+    /// Run the hook for a saved image, the error is its stderr if it exits with failure.
+    pub async fn run(&self, path: &Path, url: &str, link: &str) -> Result<(), String> {
+        let _permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        let path = path.to_string_lossy();
+        let mut args = self.command.0.iter().map(|arg| {
+            arg.replace("{path}", &path)
+                .replace("{url}", url)
+                .replace("{link}", link)
+        });
+        let program = args.next().expect("hook command is never empty");
+        tracing::debug!("running hook {} for {}", program, path);
+        let output = Command::new(&program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| format!("running {} with error {}", program, e))?;
+        if !output.stdout.is_empty() {
+            tracing::debug!(
+                "hook output of {}: {}",
+                path,
+                String::from_utf8_lossy(&output.stdout).trim_end()
+            );
+        }
+        if !output.status.success() {
+            return Err(format!(
+                "{} {}: {}",
+                program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_hook_command() {
+        use super::HookCommand;
+
+        let command: HookCommand = r#"alt-text --out "{path}.txt" '{url}' a\b "x \"y\"""#
+            .parse()
+            .unwrap();
+        assert_eq!(
+            command.0,
+            [
+                "alt-text",
+                "--out",
+                "{path}.txt",
+                "{url}",
+                "a\\b",
+                "x \"y\""
+            ]
+        );
+        assert_eq!("cmd ''".parse::<HookCommand>().unwrap().0, ["cmd", ""]);
+        assert!("cmd 'x".parse::<HookCommand>().is_err());
+        assert!("  ".parse::<HookCommand>().is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+};
+
+use crate::{downloader::Sources, utils::display_url};
+
+/// Urls listed under each domain for approval.
+const SAMPLE_URLS: usize = 3;
+
+enum Answer {
+    Yes,
+    No,
+    All,
+    Decide,
+}
+
+/// Ask until a valid answer is read, EOF denies.
+fn ask(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    prompt: &str,
+    choices: &str,
+) -> std::io::Result<Answer> {
+    loop {
+        write!(output, "{} [{}] ", prompt, choices)?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            writeln!(output)?;
+            return Ok(Answer::No);
+        }
+        let answer = match line.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => (Answer::Yes, 'y'),
+            "n" | "no" => (Answer::No, 'n'),
+            "a" | "all" => (Answer::All, 'a'),
+            "d" | "decide" => (Answer::Decide, 'd'),
+            _ => continue,
+        };
+        if choices.contains(answer.1) {
+            return Ok(answer.0);
+        }
+    }
+}
+
+/// List urls grouped by domain and keep approved ones. For each domain, `y` approves it,
+/// `n` denies it, `a` approves it and all remaining ones, and `d` asks for each url.
+pub fn confirm_sources(
+    mut sources: Sources,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> std::io::Result<Sources> {
+    let mut domains: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for url in sources.keys() {
+        let domain = match reqwest::Url::parse(url) {
+            Ok(parsed) if parsed.scheme() == "data" => "data uri".to_string(),
+            Ok(parsed) => parsed.host_str().unwrap_or_default().to_string(),
+            Err(_) => String::new(),
+        };
+        domains.entry(domain).or_default().push(url.clone());
+    }
+    let mut approved = Vec::new();
+    let mut approve_all = false;
+    for (domain, mut urls) in domains {
+        urls.sort();
+        if approve_all {
+            approved.extend(urls);
+            continue;
+        }
+        writeln!(output, "{} ({} urls)", domain, urls.len())?;
+        for url in urls.iter().take(SAMPLE_URLS) {
+            writeln!(output, "  {}", display_url(url))?;
+        }
+        if urls.len() > SAMPLE_URLS {
+            writeln!(output, "  ...")?;
+        }
+        match ask(input, output, "download from it?", "y/n/a/d")? {
+            Answer::Yes => approved.extend(urls),
+            Answer::No => (),
+            Answer::All => {
+                approve_all = true;
+                approved.extend(urls);
+            }
+            Answer::Decide => {
+                for url in urls {
+                    let prompt = format!("  {}?", display_url(&url));
+                    if let Answer::Yes = ask(input, output, &prompt, "y/n")? {
+                        approved.push(url);
+                    }
+                }
+            }
+        }
+    }
+    let total = sources.len();
+    let approved: Sources = approved
+        .into_iter()
+        .filter_map(|url| sources.remove_entry(&url))
+        .collect();
+    tracing::info!("{} of {} urls approved", approved.len(), total);
+    Ok(approved)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_confirm_sources() {
+        use super::confirm_sources;
+        use crate::downloader::Sources;
+
+        let sources: Sources = [
+            "https://a.com/1.png",
+            "https://a.com/2.png",
+            "https://b.com/1.png",
+            "https://c.com/1.png",
+            "https://c.com/2.png",
+            "https://d.com/1.png",
+        ]
+        .iter()
+        .map(|url| (url.to_string(), Vec::new()))
+        .collect();
+        // a.com decided per url, b.com denied after an invalid answer, then all approved
+        let mut input = "d\nn\ny\nx\nn\na\n".as_bytes();
+        let mut output = Vec::new();
+        let approved = confirm_sources(sources, &mut input, &mut output).unwrap();
+        let mut urls: Vec<_> = approved.keys().map(String::as_str).collect();
+        urls.sort_unstable();
+        assert_eq!(
+            urls,
+            [
+                "https://a.com/2.png",
+                "https://c.com/1.png",
+                "https://c.com/2.png",
+                "https://d.com/1.png"
+            ]
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("b.com (1 urls)"));
+    }
+}
@@ -0,0 +1,687 @@
+//! Download images in markdown and rewrite the links. The command line is run by `run`,
+//! binaries built on this crate can `plugin::register` their own plugins before it.
+
+use std::path::PathBuf;
+
+use clap::{Args, FromArgMatches, IntoApp, Parser, Subcommand};
+use tracing_subscriber::FmtSubscriber;
+
+// HTTP/3 of reqwest is only built with its unstable cfg
+#[cfg(all(feature = "http3", not(reqwest_unstable)))]
+compile_error!("the http3 feature needs RUSTFLAGS=\"--cfg reqwest_unstable\"");
+
+mod asset;
+use asset::AssetType;
+
+mod audit;
+use audit::{audit_markdown, AuditFormat};
+
+mod budget;
+mod cache;
+
+mod check;
+use check::check_markdown;
+
+mod checksums;
+use checksums::verify_output;
+
+mod convert;
+use convert::Convert;
+
+mod data;
+
+mod downloader;
+use downloader::{process_mapping, process_markdown, process_stdin, process_url_list};
+
+mod prune;
+use prune::prune_images;
+
+mod git;
+mod interactive;
+
+mod hook;
+use hook::HookCommand;
+
+mod manifest;
+mod metadata;
+mod optimize;
+
+pub mod plugin;
+use plugin::{CapturePattern, PluginRef};
+
+mod placeholder;
+use placeholder::PlaceholderKind;
+
+mod preset;
+use preset::Preset;
+
+mod profile;
+mod progress;
+mod provenance;
+mod utils;
+mod regexp;
+
+mod report;
+use report::{FailOn, ReportFormat};
+
+mod robots;
+
+mod rollback;
+use rollback::rollback_markdown;
+
+mod shutdown;
+use shutdown::Shutdown;
+
+mod server;
+use server::serve;
+
+mod resolve;
+use resolve::ResolveOverride;
+
+mod srcset;
+
+mod storage;
+use storage::{DedupStrategy, StorageKind};
+
+mod svg;
+
+mod template;
+use template::split_link_prefix;
+
+mod throttle;
+use throttle::{Bandwidth, ByteSize};
+
+mod uploader;
+use uploader::process_markdown_reverse;
+
+mod watcher;
+use watcher::watch_markdown;
+
+#[derive(Parser)]
+#[clap(version = "1.0", author = "ihciah <ihciah@gmail.com>")]
+pub struct Opts {
+    #[clap(subcommand)]
+    pub(crate) command: Option<Command>,
+    /// Take input, output dir and link prefix not given from the layout of a site generator,
+    /// `auto` detects it from config files in the working dir.
+    #[clap(long, arg_enum)]
+    pub(crate) preset: Option<Preset>,
+    /// Directories of markdown files, may be given multiple times or comma separated.
+    #[clap(
+        short,
+        long,
+        use_delimiter = true,
+        multiple_occurrences = true,
+        default_value = "source"
+    )]
+    pub(crate) input: Vec<String>,
+    /// Glob patterns of markdown files under input, like `**/*.markdown`.
+    #[clap(long, use_delimiter = true, default_value = "**/*.md")]
+    pub(crate) include: Vec<glob::Pattern>,
+    /// Glob patterns of files under input to skip, like `drafts/**`.
+    #[clap(long, use_delimiter = true)]
+    pub(crate) exclude: Vec<glob::Pattern>,
+    #[clap(short, long, default_value = "public/images")]
+    pub(crate) output_dir: String,
+    /// Prefix of rewritten links, it may contain `{relpath}`, `{slug}` and `{year}` of the
+    /// markdown file, like `/images/{year}/{slug}`, images are saved in sub directories then.
+    #[clap(short, long, default_value = "/images")]
+    pub(crate) link_prefix: String,
+    /// Write links relative to each markdown file, like `../../images/a.png`, instead of
+    /// under link prefix. Only for local and git-lfs storages.
+    #[clap(long)]
+    pub(crate) relative_links: bool,
+    /// Part of link prefix with variables, split off after parsing.
+    #[clap(skip)]
+    pub(crate) link_template: Option<String>,
+    /// Client kept across runs by long-running modes, or one is built for each run.
+    #[clap(skip)]
+    pub(crate) client: Option<reqwest::Client>,
+    /// Timeout of whole requests in seconds, including reading the body.
+    #[clap(short, long, parse(try_from_str), default_value = "60")]
+    pub(crate) timeout_sec: u32,
+    /// Limit total download speed, like `10MB/s` or `512KiB/s`.
+    #[clap(long)]
+    pub(crate) max_bandwidth: Option<Bandwidth>,
+    /// Limit download speed from each host.
+    #[clap(long)]
+    pub(crate) max_host_bandwidth: Option<Bandwidth>,
+    /// Download at most this many images in a run, the rest are deferred to later runs.
+    /// Images saved before or taken from cache are not counted.
+    #[clap(long)]
+    pub(crate) max_downloads: Option<usize>,
+    /// Stop starting downloads once this many bytes are received in a run, like `2GB`, the
+    /// rest are deferred to later runs.
+    #[clap(long)]
+    pub(crate) max_total_bytes: Option<ByteSize>,
+    /// Timeout of connecting to hosts in seconds.
+    #[clap(long, parse(try_from_str), default_value = "10")]
+    pub(crate) connect_timeout: u32,
+    /// Give up a download if no data is received for this many seconds.
+    #[clap(long, parse(try_from_str), default_value = "20")]
+    pub(crate) read_timeout: u32,
+    /// Maximum idle connections kept per host, unlimited if not given.
+    #[clap(long)]
+    pub(crate) pool_max_idle: Option<usize>,
+    /// Close idle connections after this many seconds.
+    #[clap(long, parse(try_from_str), default_value = "90")]
+    pub(crate) pool_idle_timeout: u32,
+    /// Interval of TCP keepalive probes in seconds, disabled if not given.
+    #[clap(long)]
+    pub(crate) tcp_keepalive: Option<u32>,
+    /// Adjust HTTP/2 flow control window to the bandwidth-delay product, it speeds up large
+    /// downloads over HTTP/2 connections with high latency.
+    #[clap(long)]
+    pub(crate) http2_adaptive_window: bool,
+    /// Connect with HTTP/3 directly, for hosts known to support it. There is no fallback
+    /// to HTTP/1.1 or HTTP/2, so every request to a host without QUIC fails.
+    #[cfg(feature = "http3")]
+    #[clap(long)]
+    pub(crate) http3: bool,
+    /// Resolve the host to the ip instead of asking DNS, like `img.example.com:1.2.3.4`.
+    #[clap(long, multiple_occurrences = true)]
+    pub(crate) resolve: Vec<ResolveOverride>,
+    /// Resolve hosts with the JSON API of a DNS-over-HTTPS server, like
+    /// `https://cloudflare-dns.com/dns-query`.
+    #[clap(long)]
+    pub(crate) doh: Option<String>,
+    /// Refuse to download from loopback, private and link-local addresses, including hosts
+    /// resolving to them and redirects. Hosts given by `--resolve` and proxies are trusted.
+    #[clap(long)]
+    pub(crate) block_private_ips: bool,
+    /// Only connect to hosts over IPv4.
+    #[clap(long, conflicts_with = "ipv6")]
+    pub(crate) ipv4: bool,
+    /// Only connect to hosts over IPv6.
+    #[clap(long)]
+    pub(crate) ipv6: bool,
+    /// User-Agent sent to hosts, rotated per request if given multiple times.
+    #[clap(long, multiple_occurrences = true)]
+    pub(crate) user_agent: Vec<String>,
+    /// File with one User-Agent per line to rotate among, added to `--user-agent`.
+    #[clap(long)]
+    pub(crate) user_agent_file: Option<PathBuf>,
+    /// Json file of request profiles of hosts, see README.
+    #[clap(long)]
+    pub(crate) profiles: Option<PathBuf>,
+    #[clap(short, long, parse(try_from_str), default_value = "50")]
+    pub(crate) current_limit: u32,
+    /// Number of markdown files read or written in parallel.
+    #[clap(long, parse(try_from_str), default_value = "16")]
+    pub(crate) file_limit: usize,
+    /// Only process markdown files changed since the git ref(commit, branch or tag).
+    #[clap(long)]
+    pub(crate) changed_since: Option<String>,
+    /// Keep running and process markdown files when they change.
+    #[clap(long)]
+    pub(crate) watch: bool,
+    /// Read a markdown document from stdin and write the rewritten one to stdout.
+    #[clap(long, conflicts_with_all = &["watch", "reverse"])]
+    pub(crate) stdin: bool,
+    /// List collected urls by domain and ask which to download before any downloading or
+    /// rewriting, denied ones are left as they are.
+    #[clap(long, conflicts_with_all = &["stdin", "watch"])]
+    pub(crate) interactive: bool,
+    /// Download urls listed in the file, one per line, instead of scanning markdown, and
+    /// print the url to link mapping.
+    #[clap(long, conflicts_with_all = &["watch", "reverse", "stdin"])]
+    pub(crate) url_list: Option<PathBuf>,
+    /// Log more, `-v` for debug and `-vv` for trace.
+    #[clap(short, long, parse(from_occurrences))]
+    pub(crate) verbose: u8,
+    /// Log less, `-q` for warnings and `-qq` for errors only. Progress bar is hidden.
+    #[clap(short, long, parse(from_occurrences))]
+    pub(crate) quiet: u8,
+    #[clap(long, arg_enum, default_value = "text")]
+    pub(crate) log_format: LogFormat,
+    /// Hide progress bar, useful for CI logs.
+    #[clap(long)]
+    pub(crate) no_progress: bool,
+    /// Emit a machine-readable report of every url.
+    #[clap(long, arg_enum)]
+    pub(crate) report: Option<ReportFormat>,
+    /// Exit with failure on `error` of processing, `any` failed url, or failed urls over
+    /// `threshold:N%`.
+    #[clap(long, default_value = "error")]
+    pub(crate) fail_on: FailOn,
+    /// Write report to file instead of stdout.
+    #[clap(long)]
+    pub(crate) report_file: Option<PathBuf>,
+    /// Write a `.json` sidecar next to each saved image, recording its source url, response
+    /// headers, download time and hash.
+    #[clap(long)]
+    pub(crate) provenance: bool,
+    /// Write the url to link mapping of this run to the file, as csv if it ends with `.csv`,
+    /// or json.
+    #[clap(long)]
+    pub(crate) export_mapping: Option<PathBuf>,
+    /// Url to link mapping exported by `--export-mapping`, used by `--no-download` and
+    /// `rollback` instead of manifest.
+    #[clap(long)]
+    pub(crate) mapping: Option<PathBuf>,
+    /// Only rewrite markdown with `--mapping` or manifest, without any network activity.
+    #[clap(long, conflicts_with_all = &["stdin", "watch", "reverse", "url-list", "interactive"])]
+    pub(crate) no_download: bool,
+    /// Only download images and write manifest, mapping and report, markdown files are
+    /// left untouched.
+    #[clap(long, conflicts_with_all = &["no-download", "stdin", "reverse"])]
+    pub(crate) no_rewrite: bool,
+    /// Update SHA-256 of files in output dir in it after each run, in `sha256sum` format.
+    /// Check them later with `verify`.
+    #[clap(long)]
+    pub(crate) checksums: Option<PathBuf>,
+    /// Write failed urls with error and source files to this json file.
+    #[clap(long)]
+    pub(crate) failure_log: Option<PathBuf>,
+    /// Only retry urls in the failure log written by a previous run.
+    #[clap(long)]
+    pub(crate) retry_failed: Option<PathBuf>,
+    /// Manifest recording downloaded images, it is kept across runs.
+    #[clap(long, default_value = ".image-downloader.json")]
+    pub(crate) manifest: PathBuf,
+    /// Re-check images recorded in manifest with conditional requests, and replace local
+    /// copies whose remote changed.
+    #[clap(long)]
+    pub(crate) refresh: bool,
+    /// Download all images again, including those recorded in manifest, and overwrite the
+    /// saved files.
+    #[clap(long, conflicts_with = "refresh")]
+    pub(crate) force: bool,
+    /// Keep downloaded images in a user-level cache shared by all runs and sites, and use
+    /// cached ones instead of downloading again.
+    #[clap(long)]
+    pub(crate) cache: bool,
+    /// Directory of the shared cache, implies `--cache`. Defaults to `image-downloader` in
+    /// the user cache dir.
+    #[clap(long)]
+    pub(crate) cache_dir: Option<PathBuf>,
+    /// Make no network requests. Images are only taken from manifest and cache, which is
+    /// implied, others fail and are left as they are.
+    #[clap(long, conflicts_with_all = &["refresh", "force", "reverse"])]
+    pub(crate) offline: bool,
+    /// Keep the modification time of rewritten markdown files.
+    #[clap(long)]
+    pub(crate) preserve_mtime: bool,
+    /// Keep the original of rewritten markdown files as `.md.bak`.
+    #[clap(long)]
+    pub(crate) backup: bool,
+    /// Copy the original of rewritten markdown files to this dir.
+    #[clap(long)]
+    pub(crate) backup_dir: Option<PathBuf>,
+    /// Abort if the input dir has uncommitted changes in git.
+    #[clap(long)]
+    pub(crate) require_clean_git: bool,
+    /// Seconds to wait in-flight downloads after Ctrl-C before cancelling them.
+    #[clap(long, parse(try_from_str), default_value = "10")]
+    pub(crate) grace_sec: u64,
+    /// Re-encode downloaded JPEG and PNG images, as `webp[:quality]` or `avif[:quality[:speed]]`
+    /// (built with feature avif).
+    #[clap(long)]
+    pub(crate) convert: Option<Convert>,
+    /// Remove scripts, event handlers and external references from svg images.
+    #[clap(long)]
+    pub(crate) sanitize_svg: bool,
+    /// Remove EXIF, XMP and comments, including GPS coordinates, from JPEG, PNG and WebP images.
+    #[clap(long)]
+    pub(crate) strip_metadata: bool,
+    /// Optimize PNG images with oxipng and JPEG images with jpegtran losslessly.
+    #[clap(long)]
+    pub(crate) optimize: bool,
+    /// Generate resized variants narrower than the image in these widths, e.g. `480,800,1200`,
+    /// and rewrite the image with srcset template.
+    #[clap(long, use_delimiter = true)]
+    pub(crate) srcset: Vec<u32>,
+    /// Html to render images with variants, `{src}`, `{srcset}`, `{alt}`, `{placeholder}`,
+    /// `{width}` and `{height}` are replaced.
+    #[clap(long, default_value = srcset::DEFAULT_TEMPLATE)]
+    pub(crate) srcset_template: String,
+    /// Generate placeholder of images for blur-up loading.
+    #[clap(long, arg_enum)]
+    pub(crate) placeholder: Option<PlaceholderKind>,
+    /// Placeholders are written into it, keyed by link.
+    #[clap(long, default_value = "image-placeholders.json")]
+    pub(crate) placeholder_file: PathBuf,
+    /// Write width and height of images into it, keyed by link.
+    #[clap(long)]
+    pub(crate) dimensions_file: Option<PathBuf>,
+    /// Try the closest snapshot in Internet Archive if a link is dead.
+    #[clap(long)]
+    pub(crate) wayback: bool,
+    /// Resolve protocol-relative `//host/a.png` and root-relative `/a.png` links against it.
+    /// Links under link prefix are still treated as local.
+    #[clap(long, parse(try_from_str = reqwest::Url::parse))]
+    pub(crate) base_url: Option<reqwest::Url>,
+    /// Query params stripped before deduplicating urls, e.g. `utm_*,ref`.
+    #[clap(long, use_delimiter = true)]
+    pub(crate) strip_params: Vec<String>,
+    /// Decode inline base64 `data:` images into files and link them instead.
+    #[clap(long)]
+    pub(crate) extract_data_uri: bool,
+    /// Also download assets of these types, like `mp4,webm,pdf`, linked by plain markdown
+    /// links or `<video>`, `<audio>` and `<source>` tags.
+    #[clap(long, use_delimiter = true)]
+    pub(crate) asset_types: Vec<AssetType>,
+    /// Also scan `.css` files under input, and download and rewrite `url(...)` in them and
+    /// in inline styles.
+    #[clap(long)]
+    pub(crate) css: bool,
+    /// Compiled-in plugins extracting and rewriting links in other formats, comma
+    /// separated, like `hugo-shortcodes`.
+    #[clap(long, use_delimiter = true)]
+    pub(crate) plugins: Vec<PluginRef>,
+    /// Additional regex capturing urls in group `url`, like `data-bg="(?P<url>http[^"]+)"`,
+    /// can be given multiple times. Captured urls are replaced with the new links as is.
+    #[clap(long, multiple_occurrences = true)]
+    pub(crate) capture: Vec<CapturePattern>,
+    /// Directories of JSON, YAML and TOML data files, like `data` of Hugo, whose image urls
+    /// are downloaded and rewritten in place.
+    #[clap(long, use_delimiter = true, multiple_occurrences = true)]
+    pub(crate) data_dir: Vec<String>,
+    /// Key paths of values in data files to download, dot separated with `*` matching a
+    /// key, like `gallery.src`. Array items add no key. Without them, values of all keys
+    /// with image extensions are taken.
+    #[clap(long, use_delimiter = true)]
+    pub(crate) data_keys: Vec<String>,
+    /// Fetch robots.txt of each host and skip urls disallowed for our user agent.
+    #[clap(long)]
+    pub(crate) respect_robots: bool,
+    /// Command run after each image is downloaded and saved, like `'cmd {path} {url}'`.
+    /// `{path}` is the file in output dir, `{url}` the original url and `{link}` the new
+    /// link. Failures are reported in the summary without failing the image. Only run
+    /// with local and git-lfs storages.
+    #[clap(long)]
+    pub(crate) hook: Option<HookCommand>,
+    /// Maximum hook commands running in parallel.
+    #[clap(long, default_value = "4")]
+    pub(crate) hook_limit: usize,
+    /// Upload local images under link prefix to remote and rewrite links outward.
+    #[clap(long)]
+    pub(crate) reverse: bool,
+    /// Endpoint images are uploaded to by WebDAV and sftp storages.
+    #[clap(long)]
+    pub(crate) remote_endpoint: Option<String>,
+    /// Public url prefix of uploaded images, defaults to remote endpoint.
+    #[clap(long)]
+    pub(crate) remote_prefix: Option<String>,
+    /// Where images are saved to. Remote endpoint is used as WebDAV url, or as scp style
+    /// `host:path` target uploaded to over ssh.
+    #[clap(long, arg_enum, default_value = "local")]
+    pub(crate) storage: StorageKind,
+    /// How local storage saves an image whose content is already saved under another name,
+    /// hardlink and symlink share one copy on disk.
+    #[clap(long, arg_enum, default_value = "copy")]
+    pub(crate) dedup_strategy: DedupStrategy,
+    /// Other directories whose files are linked to by `--dedup-strategy`, like page bundles
+    /// of other posts. Hardlinks need them on the same filesystem as output dir.
+    #[clap(long, multiple_occurrences = true)]
+    pub(crate) dedup_dir: Vec<String>,
+    /// S3-compatible bucket used by s3 storage.
+    /// Credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    #[clap(long)]
+    pub(crate) s3_bucket: Option<String>,
+    /// S3 endpoint, e.g. `https://<account>.r2.cloudflarestorage.com`.
+    #[clap(long)]
+    pub(crate) s3_endpoint: Option<String>,
+    #[clap(long, default_value = "auto")]
+    pub(crate) s3_region: String,
+    /// Key prefix of uploaded objects.
+    #[clap(long, default_value = "")]
+    pub(crate) s3_prefix: String,
+    /// Public url of the bucket used for rewritten links.
+    #[clap(long)]
+    pub(crate) s3_public_url: Option<String>,
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check for remaining remote images without downloading or writing, exit with 1 if any.
+    Check(CheckOpts),
+    /// Delete images in output dir no longer referenced by any markdown file.
+    Prune(PruneOpts),
+    /// Report remaining remote images and local images pointing at missing files.
+    Audit(AuditOpts),
+    /// Hash files in output dir again and compare them with `--checksums`, exit with 1 if
+    /// any is corrupted or missing.
+    Verify,
+    /// Serve an HTTP API processing markdown on demand with `POST /process`, the client and
+    /// its connections are kept across requests.
+    Serve(ServeOpts),
+    /// Rewrite local links back to the original urls with `--mapping` or manifest.
+    Rollback(RollbackOpts),
+}
+
+#[derive(Args)]
+pub struct RollbackOpts {
+    /// Also delete the downloaded files and forget them in manifest.
+    #[clap(long)]
+    pub(crate) delete: bool,
+}
+
+#[derive(Args)]
+pub struct ServeOpts {
+    /// Address to listen on.
+    #[clap(long, default_value = "127.0.0.1:8787")]
+    pub(crate) listen: std::net::SocketAddr,
+}
+
+#[derive(Args)]
+pub struct CheckOpts {
+    /// Also check local images under link prefix exist in output dir.
+    #[clap(long)]
+    pub(crate) verify_local: bool,
+}
+
+#[derive(Args)]
+pub struct PruneOpts {
+    /// Only list the unreferenced images without deleting.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct AuditOpts {
+    #[clap(long, arg_enum, default_value = "table")]
+    pub(crate) format: AuditFormat,
+}
+
+/// Exit code when check finds remote or missing images.
+const EXIT_CHECK_FAILED: i32 = 1;
+/// Exit code when failed downloads exceed `--fail-on`.
+const EXIT_DOWNLOAD_FAILED: i32 = 1;
+/// Exit code when check itself or precondition fails.
+const EXIT_ERROR: i32 = 2;
+/// Exit code when interrupted by Ctrl-C, the same as shells.
+const EXIT_INTERRUPTED: i32 = 130;
+
+fn log_level(verbose: u8, quiet: u8) -> tracing::Level {
+    match verbose as i8 - quiet as i8 {
+        i8::MIN..=-2 => tracing::Level::ERROR,
+        -1 => tracing::Level::WARN,
+        0 => tracing::Level::INFO,
+        1 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    }
+}
+
+/// Run the command line with the arguments of the process, which exits with the failure
+/// code if it fails.
+pub async fn run() {
+    let matches = Opts::into_app().get_matches();
+    let mut opts = Opts::from_arg_matches(&matches).expect("matches are validated");
+    let builder = FmtSubscriber::builder()
+        .with_writer(std::io::stderr)
+        .with_max_level(log_level(opts.verbose, opts.quiet));
+    match opts.log_format {
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+        LogFormat::Json => tracing::subscriber::set_global_default(builder.json().finish()),
+    }
+    .expect("setting default subscriber failed");
+    if let Some(preset) = opts.preset {
+        preset.apply(&mut opts, |name| matches.occurrences_of(name) > 0);
+    }
+    let (link_prefix, link_template) = split_link_prefix(&opts.link_prefix);
+    opts.link_prefix = link_prefix;
+    opts.link_template = link_template;
+    if opts.css {
+        opts.include
+            .push(glob::Pattern::new("**/*.css").expect("valid pattern"));
+    }
+    match &opts.command {
+        Some(Command::Check(check)) => {
+            match check_markdown(&opts, check) {
+                Ok(true) => tracing::info!("check passed"),
+                Ok(false) => std::process::exit(EXIT_CHECK_FAILED),
+                Err(e) => {
+                    tracing::error!("check markdown in error: {}", e);
+                    std::process::exit(EXIT_ERROR);
+                }
+            }
+            return;
+        }
+        Some(Command::Rollback(rollback)) => {
+            if let Err(e) = rollback_markdown(&opts, rollback) {
+                tracing::error!("rollback in error: {}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+            return;
+        }
+        Some(Command::Prune(prune)) => {
+            if let Err(e) = prune_images(&opts, prune) {
+                tracing::error!("prune images in error: {}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+            return;
+        }
+        Some(Command::Verify) => {
+            let path = match &opts.checksums {
+                Some(path) => path,
+                None => {
+                    tracing::error!("verify requires --checksums");
+                    std::process::exit(EXIT_ERROR);
+                }
+            };
+            match verify_output(opts.output_dir.as_ref(), path) {
+                Ok(true) => tracing::info!("verify passed"),
+                Ok(false) => std::process::exit(EXIT_CHECK_FAILED),
+                Err(e) => {
+                    tracing::error!("verify checksums in error: {}", e);
+                    std::process::exit(EXIT_ERROR);
+                }
+            }
+            return;
+        }
+        Some(Command::Audit(audit)) => {
+            let ret = audit_markdown(&opts, true).and_then(|a| Ok(a.print(audit.format)?));
+            if let Err(e) = ret {
+                tracing::error!("audit markdown in error: {}", e);
+                std::process::exit(EXIT_ERROR);
+            }
+            return;
+        }
+        Some(Command::Serve(_)) | None => {}
+    }
+
+    tracing::info!(
+        "will download image for markdown files in {} to {} with link prefix {}, timeout is {} sec",
+        opts.input.join(", "),
+        opts.output_dir,
+        opts.link_prefix,
+        opts.timeout_sec,
+    );
+
+    let _ = std::fs::create_dir_all(&opts.output_dir);
+
+    if let Some(path) = &opts.user_agent_file {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => opts.user_agent.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(str::to_string),
+            ),
+            Err(e) => {
+                tracing::error!(
+                    "reading user agents from {} in error: {}",
+                    path.display(),
+                    e
+                );
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+    }
+
+    if opts.reverse && matches!(opts.storage, StorageKind::Local | StorageKind::GitLfs) {
+        tracing::error!("--reverse requires a remote storage, like --storage webdav");
+        std::process::exit(EXIT_ERROR);
+    }
+
+    if opts.offline && !matches!(opts.storage, StorageKind::Local | StorageKind::GitLfs) {
+        tracing::error!("--offline only works with local and git-lfs storages");
+        std::process::exit(EXIT_ERROR);
+    }
+
+    if opts.require_clean_git {
+        for input in opts.input.iter() {
+            match git::is_clean(input.as_ref()) {
+                Ok(true) => {}
+                Ok(false) => {
+                    tracing::error!(
+                        "{} has uncommitted changes, commit or stash them first",
+                        input
+                    );
+                    std::process::exit(EXIT_ERROR);
+                }
+                Err(e) => {
+                    tracing::error!("checking git status in error: {}", e);
+                    std::process::exit(EXIT_ERROR);
+                }
+            }
+        }
+    }
+
+    let shutdown = Shutdown::listen();
+    let fail_on = opts.fail_on;
+    let ret = if let Some(Command::Serve(ServeOpts { listen })) = opts.command {
+        serve(opts, listen, shutdown.clone()).await.map(|_| None)
+    } else if opts.reverse {
+        process_markdown_reverse(opts, shutdown.clone())
+            .await
+            .map(|_| None)
+    } else if opts.watch {
+        watch_markdown(opts, shutdown.clone()).await.map(|_| None)
+    } else if opts.no_download {
+        process_mapping(opts).map(Some)
+    } else if opts.stdin {
+        process_stdin(opts, shutdown.clone()).await.map(Some)
+    } else if let Some(path) = opts.url_list.clone() {
+        process_url_list(opts, &path, shutdown.clone())
+            .await
+            .map(Some)
+    } else {
+        process_markdown(opts, shutdown.clone()).await.map(Some)
+    };
+    let exit_code = match ret {
+        Err(e) => {
+            tracing::error!("process markdown in error: {}", e);
+            Some(EXIT_ERROR)
+        }
+        Ok(Some(report)) if report.is_failed(fail_on) => Some(EXIT_DOWNLOAD_FAILED),
+        Ok(_) => None,
+    };
+    if shutdown.is_triggered() {
+        tracing::warn!("image downloader interrupted");
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    if let Some(code) = exit_code {
+        tracing::error!("image downloader failed");
+        std::process::exit(code);
+    }
+    tracing::info!("image downloader finished");
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::write_atomic;
+
+/// Record of downloaded images kept across runs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub images: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    /// The new link written into markdown.
+    pub link: String,
+    #[serde(flatten)]
+    pub attrs: ImageAttrs,
+}
+
+/// Generated attributes and response validators of an image, kept for images skipped in
+/// later runs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImageAttrs {
+    /// Srcset of resized variants.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub srcset: Option<String>,
+    /// BlurHash or base64 data uri of a tiny image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// ETag of the response, sent as `If-None-Match` by `--refresh`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// Last-Modified of the response, sent as `If-Modified-Since` by `--refresh`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl ImageAttrs {
+    pub fn is_empty(&self) -> bool {
+        self.srcset.is_none()
+            && self.placeholder.is_none()
+            && self.width.is_none()
+            && self.height.is_none()
+            && self.etag.is_none()
+            && self.last_modified.is_none()
+    }
+}
+
+/// Size of an image.
+#[derive(Serialize, Debug)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Manifest {
+    /// Url to link mapping of recorded images.
+    pub fn mapping(&self) -> HashMap<String, String> {
+        self.images
+            .iter()
+            .map(|(url, entry)| (url.clone(), entry.link.clone()))
+            .collect()
+    }
+
+    /// Load manifest, an empty one is returned if the file does not exist.
+    pub fn load(path: &Path) -> Result<Self, serde_json::Error> {
+        match std::fs::File::open(path) {
+            Ok(f) => serde_json::from_reader(f),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(serde_json::Error::io(e)),
+        }
+    }
+
+    /// Save manifest atomically, so it is never half written.
+    pub fn save(&self, path: &Path) -> Result<(), serde_json::Error> {
+        let content = serde_json::to_vec_pretty(self)?;
+        write_atomic(path, &content, false).map_err(serde_json::Error::io)
+    }
+
+    /// Record the url to link mapping and attributes of them.
+    pub fn update(
+        &mut self,
+        mapping: &HashMap<String, String>,
+        attrs: &HashMap<String, ImageAttrs>,
+    ) {
+        for (url, link) in mapping {
+            let entry = ManifestEntry {
+                link: link.clone(),
+                attrs: attrs.get(url).cloned().unwrap_or_default(),
+            };
+            self.images.insert(url.clone(), entry);
+        }
+    }
+
+    /// Dimensions keyed by link.
+    pub fn dimensions(&self) -> BTreeMap<&str, Dimensions> {
+        self.images
+            .values()
+            .filter_map(|entry| {
+                let dimensions = Dimensions {
+                    width: entry.attrs.width?,
+                    height: entry.attrs.height?,
+                };
+                Some((entry.link.as_str(), dimensions))
+            })
+            .collect()
+    }
+
+    /// Placeholders keyed by link.
+    pub fn placeholders(&self) -> BTreeMap<&str, &str> {
+        self.images
+            .values()
+            .filter_map(|entry| Some((entry.link.as_str(), entry.attrs.placeholder.as_deref()?)))
+            .collect()
+    }
+}
@@ -0,0 +1,178 @@
+use bytes::Bytes;
+
+/// Remove EXIF, XMP, IPTC and comments from JPEG, PNG and WebP images, which may include
+/// GPS coordinates. Color profiles are kept. The format is detected by content, None is
+/// returned for other formats, malformed images and images without metadata.
+pub fn strip_metadata(content: &[u8]) -> Option<Bytes> {
+    let stripped = if content.starts_with(&[0xFF, 0xD8]) {
+        strip_jpeg(content)?
+    } else if content.starts_with(b"\x89PNG\r\n\x1a\n") {
+        strip_png(content)?
+    } else if content.len() >= 12 && &content[..4] == b"RIFF" && &content[8..12] == b"WEBP" {
+        strip_webp(content)?
+    } else {
+        return None;
+    };
+    if stripped.len() == content.len() {
+        return None;
+    }
+    Some(stripped.into())
+}
+
+fn strip_jpeg(content: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = content[..2].to_vec();
+    let mut pos = 2;
+    loop {
+        if content.get(pos)? != &0xFF {
+            return None;
+        }
+        let marker = *content.get(pos + 1)?;
+        // fill bytes
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        // markers without length
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            stripped.extend_from_slice(&[0xFF, marker]);
+            pos += 2;
+            continue;
+        }
+        let len = u16::from_be_bytes([*content.get(pos + 2)?, *content.get(pos + 3)?]) as usize;
+        if len < 2 {
+            return None;
+        }
+        let end = pos + 2 + len;
+        let segment = content.get(pos..end)?;
+        // entropy coded data follows start of scan, it is copied as is
+        if marker == 0xDA {
+            stripped.extend_from_slice(&content[pos..]);
+            return Some(stripped);
+        }
+        let payload = &segment[4..];
+        let keep = match marker {
+            // JFIF and Adobe color transform
+            0xE0 | 0xEE => true,
+            // ICC profile
+            0xE2 => payload.starts_with(b"ICC_PROFILE\0"),
+            // EXIF, XMP and other application data, IPTC and comments
+            0xE1 | 0xE3..=0xED | 0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            stripped.extend_from_slice(segment);
+        }
+        pos = end;
+    }
+}
+
+fn strip_png(content: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = content[..8].to_vec();
+    let mut pos = 8;
+    while pos < content.len() {
+        let len = u32::from_be_bytes(content.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let chunk_type = content.get(pos + 4..pos + 8)?;
+        // length, type, data and crc
+        let end = pos + 12 + len;
+        let chunk = content.get(pos..end)?;
+        if !matches!(chunk_type, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME") {
+            stripped.extend_from_slice(chunk);
+        }
+        pos = end;
+    }
+    Some(stripped)
+}
+
+fn strip_webp(content: &[u8]) -> Option<Vec<u8>> {
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+
+    let mut stripped = content[..12].to_vec();
+    let mut pos = 12;
+    while pos < content.len() {
+        let fourcc = content.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes(content.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // chunks are padded to even size
+        let end = (pos + 8 + len + (len & 1)).min(content.len());
+        let chunk = content.get(pos..end)?;
+        match fourcc {
+            b"EXIF" | b"XMP " => (),
+            b"VP8X" => {
+                let flags_pos = stripped.len() + 8;
+                stripped.extend_from_slice(chunk);
+                *stripped.get_mut(flags_pos)? &= !(EXIF_FLAG | XMP_FLAG);
+            }
+            _ => stripped.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+    let riff_size = (stripped.len() - 8) as u32;
+    stripped[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_metadata;
+
+    #[test]
+    fn test_strip_jpeg() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x04, b'J', b'F']);
+        jpeg.extend_from_slice(&[0xFF, 0xE1, 0x00, 0x06, b'E', b'x', b'i', b'f']);
+        jpeg.extend_from_slice(&[0xFF, 0xFE, 0x00, 0x03, b'c']);
+        jpeg.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+        let stripped = strip_metadata(&jpeg).unwrap();
+        assert_eq!(
+            &stripped[..],
+            &[
+                0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, b'J', b'F', 0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34,
+                0xFF, 0xD9
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strip_png() {
+        use image::{ImageFormat, RgbImage};
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbImage::new(4, 4)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        assert!(strip_metadata(&png).is_none());
+
+        // insert a text chunk after IHDR
+        let mut with_text = png[..33].to_vec();
+        with_text.extend_from_slice(&[0, 0, 0, 3]);
+        with_text.extend_from_slice(b"tEXtabc");
+        with_text.extend_from_slice(&[0, 0, 0, 0]);
+        with_text.extend_from_slice(&png[33..]);
+        assert_eq!(&strip_metadata(&with_text).unwrap()[..], &png[..]);
+    }
+
+    #[test]
+    fn test_strip_webp() {
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        webp.extend_from_slice(b"VP8X");
+        webp.extend_from_slice(&10u32.to_le_bytes());
+        webp.extend_from_slice(&[0x0C, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        webp.extend_from_slice(b"VP8L");
+        webp.extend_from_slice(&1u32.to_le_bytes());
+        webp.extend_from_slice(&[0x2F, 0]);
+        webp.extend_from_slice(b"EXIF");
+        webp.extend_from_slice(&3u32.to_le_bytes());
+        webp.extend_from_slice(&[1, 2, 3, 0]);
+        let size = (webp.len() - 8) as u32;
+        webp[4..8].copy_from_slice(&size.to_le_bytes());
+
+        let stripped = strip_metadata(&webp).unwrap();
+        assert_eq!(stripped.len(), webp.len() - 12);
+        assert_eq!(
+            &stripped[4..8],
+            &((stripped.len() - 8) as u32).to_le_bytes()
+        );
+        assert_eq!(stripped[20], 0);
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use bytes::Bytes;
+
+use crate::utils::get_path_ext;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OptimizeError {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("png error: {0}")]
+    Png(#[from] oxipng::PngError),
+    #[error("command {0} failed: {1}")]
+    Command(&'static str, String),
+}
+
+/// Whether the file will be optimized judging by its extension.
+pub fn accepts(file_name: &str) -> bool {
+    matches!(
+        get_path_ext(file_name)
+            .map(|ext| ext.to_ascii_lowercase())
+            .as_deref(),
+        Some(".png" | ".jpg" | ".jpeg")
+    )
+}
+
+/// Optimize PNG with oxipng and JPEG with the system `jpegtran` losslessly.
+/// The smaller one of the optimized and the original is returned.
+/// It is slow, so call it in blocking threads.
+pub fn optimize(file_name: &str, content: &[u8]) -> Result<Option<Bytes>, OptimizeError> {
+    let optimized = match get_path_ext(file_name)
+        .map(|ext| ext.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some(".png") => oxipng::optimize_from_memory(content, &oxipng::Options::from_preset(2))?,
+        Some(".jpg" | ".jpeg") => jpegtran(content)?,
+        _ => return Ok(None),
+    };
+    if optimized.is_empty() || optimized.len() >= content.len() {
+        return Ok(None);
+    }
+    Ok(Some(optimized.into()))
+}
+
+/// Optimize huffman tables and make it progressive, metadata is kept.
+fn jpegtran(content: &[u8]) -> Result<Vec<u8>, OptimizeError> {
+    let mut child = Command::new("jpegtran")
+        .args(["-copy", "all", "-optimize", "-progressive"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // write in another thread, or it may block on a full stdout pipe
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = content.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    writer.join().expect("unable to join writing thread")?;
+    if !output.status.success() {
+        return Err(OptimizeError::Command(
+            "jpegtran",
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_optimize_png() {
+        use super::optimize;
+        use image::{ImageFormat, RgbaImage};
+
+        // rgba with a constant alpha can be reduced
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbaImage::from_pixel(64, 64, image::Rgba([10, 20, 30, 255]))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let optimized = optimize("a.png", png.get_ref()).unwrap().unwrap();
+        assert!(optimized.len() < png.get_ref().len());
+        assert!(image::load_from_memory(&optimized).is_ok());
+        assert!(optimize("a.gif", png.get_ref()).unwrap().is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use image::{codecs::jpeg::JpegEncoder, ImageFormat};
+
+use crate::convert::ConvertError;
+
+/// Size of the image BlurHash is computed on, larger ones are slow without visible difference.
+const BLURHASH_SIZE: u32 = 32;
+/// Size of the tiny image.
+const LQIP_SIZE: u32 = 16;
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaceholderKind {
+    /// BlurHash string with 4x3 components.
+    Blurhash,
+    /// Base64 data uri of a tiny image.
+    Lqip,
+}
+
+/// Compute placeholder of the image. It is slow, so call it in blocking threads.
+pub fn placeholder(kind: PlaceholderKind, content: &[u8]) -> Result<String, ConvertError> {
+    let image = image::load_from_memory(content)?;
+    match kind {
+        PlaceholderKind::Blurhash => {
+            let small = image.thumbnail(BLURHASH_SIZE, BLURHASH_SIZE).to_rgba8();
+            blurhash::encode(4, 3, small.width(), small.height(), small.as_raw())
+                .map_err(|e| ConvertError::Placeholder(e.to_string()))
+        }
+        PlaceholderKind::Lqip => {
+            let small = image.thumbnail(LQIP_SIZE, LQIP_SIZE);
+            let mut encoded = Vec::new();
+            // jpeg is smaller, but transparency needs png
+            let mime = if small.color().has_alpha() {
+                small.write_to(&mut std::io::Cursor::new(&mut encoded), ImageFormat::Png)?;
+                "image/png"
+            } else {
+                small
+                    .to_rgb8()
+                    .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, 50))?;
+                "image/jpeg"
+            };
+            Ok(format!("data:{};base64,{}", mime, base64::encode(encoded)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_placeholder() {
+        use super::{placeholder, PlaceholderKind};
+        use image::{ImageFormat, RgbImage};
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbImage::from_pixel(100, 50, image::Rgb([200, 100, 50]))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let hash = placeholder(PlaceholderKind::Blurhash, png.get_ref()).unwrap();
+        assert_eq!(hash.len(), 4 + 2 * 4 * 3);
+        let lqip = placeholder(PlaceholderKind::Lqip, png.get_ref()).unwrap();
+        assert!(lqip.starts_with("data:image/jpeg;base64,"));
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    fmt,
+    ops::Range,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use regex::Regex;
+
+/// Extract and rewrite links in formats the built-in patterns don't cover, like shortcodes
+/// of a site. Built-in plugins and those added by `register` are enabled by name with
+/// `--plugins`.
+pub trait Plugin: Send + Sync {
+    /// Name used in `--plugins`.
+    fn name(&self) -> &'static str;
+
+    /// Byte ranges of urls in contents as written, in order and not overlapping.
+    fn extract(&self, contents: &str) -> Vec<Range<usize>>;
+
+    /// Text written in place of the url, the new link as is by default.
+    fn rewrite(&self, _url: &str, link: &str) -> String {
+        link.to_string()
+    }
+}
+
+/// Plugins added by `register`.
+static REGISTERED: RwLock<Vec<Arc<dyn Plugin>>> = RwLock::new(Vec::new());
+
+/// Make the plugin available to `--plugins`, for binaries built on this crate calling it
+/// before `run`. It takes precedence over a built-in one with the same name.
+pub fn register(plugin: Arc<dyn Plugin>) {
+    REGISTERED.write().unwrap().insert(0, plugin);
+}
+
+/// All plugins known by name, registered ones first.
+fn registry() -> Vec<Arc<dyn Plugin>> {
+    let mut plugins = REGISTERED.read().unwrap().clone();
+    plugins.push(Arc::new(HugoShortcodes::new()));
+    plugins
+}
+
+/// A plugin enabled by `--plugins`.
+#[derive(Clone)]
+pub struct PluginRef(pub Arc<dyn Plugin>);
+
+impl fmt::Debug for PluginRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.name())
+    }
+}
+
+impl FromStr for PluginRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let plugins = registry();
+        let names: Vec<_> = plugins.iter().map(|p| p.name()).collect();
+        plugins
+            .into_iter()
+            .find(|p| p.name() == s.trim())
+            .map(Self)
+            .ok_or_else(|| format!("unknown plugin {}, known: {}", s, names.join(", ")))
+    }
+}
+
+/// A user pattern given by `--capture`, capturing urls in group `url`.
+#[derive(Clone)]
+pub struct CapturePattern(Regex);
+
+impl fmt::Debug for CapturePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.as_str())
+    }
+}
+
+impl FromStr for CapturePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let regex = Regex::new(s).map_err(|e| e.to_string())?;
+        if !regex.capture_names().any(|name| name == Some("url")) {
+            return Err(format!("capture pattern {} has no group named url", s));
+        }
+        Ok(Self(regex))
+    }
+}
+
+impl Plugin for CapturePattern {
+    fn name(&self) -> &'static str {
+        "capture"
+    }
+
+    fn extract(&self, contents: &str) -> Vec<Range<usize>> {
+        self.0
+            .captures_iter(contents)
+            .filter_map(|caps| caps.name("url").map(|m| m.range()))
+            .collect()
+    }
+}
+
+/// Urls in `src` and `image` parameters of Hugo shortcodes, like
+/// `{{< figure src="https://a.com/b.png" >}}`.
+struct HugoShortcodes(Regex);
+
+impl HugoShortcodes {
+    fn new() -> Self {
+        Self(
+            Regex::new(r#"\{\{[<%]\s*[\w/-]+\s[^}]*?\b(?:src|image)\s*=\s*"(?P<url>http[^"]+)""#)
+                .unwrap(),
+        )
+    }
+}
+
+impl Plugin for HugoShortcodes {
+    fn name(&self) -> &'static str {
+        "hugo-shortcodes"
+    }
+
+    fn extract(&self, contents: &str) -> Vec<Range<usize>> {
+        self.0
+            .captures_iter(contents)
+            .filter_map(|caps| caps.name("url").map(|m| m.range()))
+            .collect()
+    }
+
+    fn rewrite(&self, _url: &str, link: &str) -> String {
+        link.replace('"', "%22")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_hugo_shortcodes() {
+        use super::PluginRef;
+
+        let plugin = "hugo-shortcodes".parse::<PluginRef>().unwrap().0;
+        let contents = r#"{{< figure src="https://a.com/b.png" title="b" >}}
+            {{% gallery image="https://a.com/c.jpg" %}} {{< youtube id="x" >}}"#;
+        let urls: Vec<_> = plugin
+            .extract(contents)
+            .into_iter()
+            .map(|r| &contents[r])
+            .collect();
+        assert_eq!(urls, ["https://a.com/b.png", "https://a.com/c.jpg"]);
+        assert!("wasm".parse::<PluginRef>().is_err());
+    }
+
+    #[test]
+    fn test_register() {
+        use super::{register, Plugin, PluginRef};
+        use std::{ops::Range, sync::Arc};
+
+        /// Urls in `{{img url}}`.
+        struct Img;
+        impl Plugin for Img {
+            fn name(&self) -> &'static str {
+                "test-img"
+            }
+
+            fn extract(&self, contents: &str) -> Vec<Range<usize>> {
+                contents
+                    .match_indices("{{img ")
+                    .filter_map(|(start, m)| {
+                        let start = start + m.len();
+                        Some(start..start + contents[start..].find("}}")?)
+                    })
+                    .collect()
+            }
+        }
+
+        assert!("test-img".parse::<PluginRef>().is_err());
+        register(Arc::new(Img));
+        let plugin = "test-img".parse::<PluginRef>().unwrap().0;
+        let contents = "{{img https://a.com/b.png}}";
+        assert_eq!(
+            &contents[plugin.extract(contents)[0].clone()],
+            "https://a.com/b.png"
+        );
+    }
+
+    #[test]
+    fn test_capture_pattern() {
+        use super::{CapturePattern, Plugin};
+
+        let pattern: CapturePattern = r#"data-bg="(?P<url>http[^"]+)""#.parse().unwrap();
+        let contents = r#"<div data-bg="https://a.com/b.png"></div>"#;
+        let ranges = pattern.extract(contents);
+        assert_eq!(&contents[ranges[0].clone()], "https://a.com/b.png");
+        assert!("data-bg=(http.*)".parse::<CapturePattern>().is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use crate::Opts;
+
+/// Static site generator whose layout gives defaults of input, output dir and link prefix.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Detect the generator from config files in the working dir.
+    Auto,
+    Hugo,
+    Hexo,
+    Jekyll,
+    Zola,
+}
+
+impl Preset {
+    /// Detect the generator of the site in dir by its config files.
+    pub fn detect(dir: &Path) -> Option<Self> {
+        let exists = |name: &str| dir.join(name).exists();
+        let contains = |name: &str, needle: &str| {
+            std::fs::read_to_string(dir.join(name)).is_ok_and(|c| c.contains(needle))
+        };
+        if ["hugo.toml", "hugo.yaml", "hugo.json"]
+            .iter()
+            .any(|n| exists(n))
+        {
+            return Some(Self::Hugo);
+        }
+        if exists("config.toml") {
+            // zola writes `base_url`, hugo `baseURL`
+            return match contains("config.toml", "base_url") {
+                true => Some(Self::Zola),
+                false => Some(Self::Hugo),
+            };
+        }
+        if exists("_config.yml") {
+            return match exists("scaffolds") || contains("package.json", "\"hexo\"") {
+                true => Some(Self::Hexo),
+                false => Some(Self::Jekyll),
+            };
+        }
+        if exists("config.yaml") || exists("config.json") {
+            return Some(Self::Hugo);
+        }
+        None
+    }
+
+    /// Input, output dir and link prefix of the generator.
+    fn layout(self) -> Option<(&'static str, &'static str, &'static str)> {
+        match self {
+            Self::Auto => None,
+            Self::Hugo | Self::Zola => Some(("content", "static/images", "/images")),
+            Self::Hexo => Some(("source/_posts", "source/images", "/images")),
+            Self::Jekyll => Some(("_posts", "assets/images", "/assets/images")),
+        }
+    }
+
+    /// Apply the layout to options not given explicitly.
+    pub fn apply(self, opts: &mut Opts, explicit: impl Fn(&str) -> bool) {
+        let preset = match self {
+            Self::Auto => match Self::detect(Path::new(".")) {
+                Some(preset) => preset,
+                None => {
+                    tracing::warn!("no known site generator detected, preset is ignored");
+                    return;
+                }
+            },
+            preset => preset,
+        };
+        let (input, output_dir, link_prefix) = preset.layout().expect("preset is resolved");
+        if !explicit("input") {
+            opts.input = vec![input.to_string()];
+        }
+        if !explicit("output-dir") {
+            opts.output_dir = output_dir.to_string();
+        }
+        if !explicit("link-prefix") {
+            opts.link_prefix = link_prefix.to_string();
+        }
+        tracing::info!(
+            "using {:?} preset, input {}, output dir {}, link prefix {}",
+            preset,
+            opts.input.join(", "),
+            opts.output_dir,
+            opts.link_prefix
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_detect_preset() {
+        use super::Preset;
+
+        let dir = std::env::temp_dir().join("image-downloader-preset");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(Preset::detect(&dir), None);
+        std::fs::write(dir.join("config.toml"), "base_url = \"https://a.com\"\n").unwrap();
+        assert_eq!(Preset::detect(&dir), Some(Preset::Zola));
+        std::fs::write(dir.join("config.toml"), "baseURL = \"https://a.com\"\n").unwrap();
+        assert_eq!(Preset::detect(&dir), Some(Preset::Hugo));
+        std::fs::remove_file(dir.join("config.toml")).unwrap();
+        std::fs::write(dir.join("_config.yml"), "title: a\n").unwrap();
+        assert_eq!(Preset::detect(&dir), Some(Preset::Jekyll));
+        std::fs::create_dir(dir.join("scaffolds")).unwrap();
+        assert_eq!(Preset::detect(&dir), Some(Preset::Hexo));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
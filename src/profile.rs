@@ -0,0 +1,169 @@
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, ClientBuilder, Proxy,
+};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+use crate::throttle::{Bandwidth, Limiter};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid host pattern: {0}")]
+    Pattern(#[from] glob::PatternError),
+    #[error("invalid header {0}")]
+    Header(String),
+    #[error("invalid bandwidth: {0}")]
+    Bandwidth(String),
+    #[error("invalid proxy: {0}")]
+    Proxy(#[from] reqwest::Error),
+}
+
+/// A profile in the file given by `--profiles`, which is a json array of them.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ProfileConfig {
+    /// Glob patterns of hosts, like `*.sinaimg.cn`.
+    hosts: Vec<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Bandwidth shared by all matching hosts, like `1MB/s`.
+    max_bandwidth: Option<String>,
+    /// Maximum downloads from matching hosts in parallel.
+    concurrency: Option<usize>,
+    timeout_sec: Option<u64>,
+    /// Proxy url for matching hosts, like `http://127.0.0.1:8080`.
+    proxy: Option<String>,
+    /// Times to retry transient failures, like timeouts and 5xx.
+    #[serde(default)]
+    retries: u32,
+    /// Delay before the first retry, doubled for each one after.
+    #[serde(default = "default_retry_delay_ms")]
+    retry_delay_ms: u64,
+}
+
+fn default_retry_delay_ms() -> u64 {
+    1000
+}
+
+/// Request settings of hosts matching the patterns.
+pub struct Profile {
+    hosts: Vec<glob::Pattern>,
+    pub headers: HeaderMap,
+    pub limiter: Option<Limiter>,
+    pub semaphore: Option<Semaphore>,
+    pub timeout: Option<Duration>,
+    /// Client with the proxy, or the shared one is used.
+    pub client: Option<Client>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+impl Profile {
+    fn from_config(
+        config: ProfileConfig,
+        client_builder: &dyn Fn() -> ClientBuilder,
+    ) -> Result<Self, ProfileError> {
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|h| glob::Pattern::new(&h.to_ascii_lowercase()))
+            .collect::<Result<_, _>>()?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in config.headers {
+            let invalid = || ProfileError::Header(format!("{}: {}", name, value));
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?,
+                HeaderValue::from_str(&value).map_err(|_| invalid())?,
+            );
+        }
+        let limiter = match config.max_bandwidth {
+            Some(bandwidth) => Some(Limiter::new(
+                bandwidth
+                    .parse::<Bandwidth>()
+                    .map_err(ProfileError::Bandwidth)?,
+            )),
+            None => None,
+        };
+        let client = match config.proxy {
+            Some(proxy) => Some(client_builder().proxy(Proxy::all(proxy)?).build()?),
+            None => None,
+        };
+        Ok(Self {
+            hosts,
+            headers,
+            limiter,
+            semaphore: config.concurrency.map(Semaphore::new),
+            timeout: config.timeout_sec.map(Duration::from_secs),
+            client,
+            retries: config.retries,
+            retry_delay: Duration::from_millis(config.retry_delay_ms),
+        })
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        self.hosts.iter().any(|p| p.matches(host))
+    }
+}
+
+/// Profiles in the order of the file, the first matching one is used.
+#[derive(Default)]
+pub struct Profiles(Vec<Profile>);
+
+impl Profiles {
+    /// Load profiles, clients of those with proxies are built from client_builder.
+    pub fn load(
+        path: &Path,
+        client_builder: &dyn Fn() -> ClientBuilder,
+    ) -> Result<Self, ProfileError> {
+        let configs: Vec<ProfileConfig> = serde_json::from_slice(&std::fs::read(path)?)?;
+        configs
+            .into_iter()
+            .map(|c| Profile::from_config(c, client_builder))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    pub fn find(&self, host: &str) -> Option<&Profile> {
+        let host = host.to_ascii_lowercase();
+        self.0.iter().find(|p| p.matches(&host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_load_profiles() {
+        use super::Profiles;
+
+        let path = std::env::temp_dir().join("image-downloader-profiles.json");
+        std::fs::write(
+            &path,
+            r#"[
+                {"hosts": ["*.sinaimg.cn"], "headers": {"Referer": "https://weibo.com/"},
+                 "concurrency": 2, "max_bandwidth": "1MB/s"},
+                {"hosts": ["raw.githubusercontent.com", "*.github.io"], "retries": 3}
+            ]"#,
+        )
+        .unwrap();
+        let profiles = Profiles::load(&path, &reqwest::Client::builder).unwrap();
+        let weibo = profiles.find("WX1.sinaimg.cn").unwrap();
+        assert_eq!(weibo.headers["referer"], "https://weibo.com/");
+        assert!(weibo.semaphore.is_some() && weibo.limiter.is_some());
+        assert_eq!(
+            profiles.find("raw.githubusercontent.com").unwrap().retries,
+            3
+        );
+        assert!(profiles.find("sinaimg.cn").is_none());
+
+        std::fs::write(&path, r#"[{"hosts": ["a.com"], "header": {}}]"#).unwrap();
+        assert!(Profiles::load(&path, &reqwest::Client::builder).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
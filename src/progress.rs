@@ -0,0 +1,74 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/// Progress of downloading, drawn as a bar on stderr.
+/// A hidden progress does nothing, it is used for `--quiet` and `--no-progress`.
+#[derive(Clone)]
+pub struct Progress {
+    bar: ProgressBar,
+    inner: Arc<ProgressInner>,
+}
+
+struct ProgressInner {
+    start: Instant,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Progress {
+    pub fn new(total: u64, enabled: bool) -> Self {
+        let bar = if enabled {
+            ProgressBar::with_draw_target(Some(total), ProgressDrawTarget::stderr())
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(
+            ProgressStyle::with_template("{elapsed_precise} [{bar:40}] {pos}/{len} {msg}")
+                .expect("invalid progress template")
+                .progress_chars("=> "),
+        );
+        Self {
+            bar,
+            inner: Arc::new(ProgressInner {
+                start: Instant::now(),
+                failed: AtomicU64::new(0),
+                bytes: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Mark one image done with its size.
+    pub fn success(&self, bytes: u64) {
+        self.inner.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.advance();
+    }
+
+    /// Mark one image failed.
+    pub fn fail(&self) {
+        self.inner.failed.fetch_add(1, Ordering::Relaxed);
+        self.advance();
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish();
+    }
+
+    fn advance(&self) {
+        let bytes = self.inner.bytes.load(Ordering::Relaxed);
+        let elapsed = self.inner.start.elapsed().as_secs_f64().max(0.001);
+        self.bar.set_message(format!(
+            "{} failed, {}, {}/s",
+            self.inner.failed.load(Ordering::Relaxed),
+            HumanBytes(bytes),
+            HumanBytes((bytes as f64 / elapsed) as u64)
+        ));
+        self.bar.inc(1);
+    }
+}
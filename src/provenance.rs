@@ -0,0 +1,48 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::utils::display_url;
+
+/// Suffix of sidecar files appended to the image file name.
+pub const SIDECAR_SUFFIX: &str = ".json";
+
+/// Where a saved image comes from, written next to it with `--provenance`.
+#[derive(Serialize, Debug)]
+pub struct Provenance {
+    /// The url found in markdown, data uris are truncated.
+    pub url: String,
+    /// The url actually downloaded, after unwrapping proxies, falling back to wayback
+    /// machine and following redirects.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetched_from: Option<String>,
+    pub downloaded_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// Hex SHA-256 of the saved file.
+    pub sha256: String,
+    pub bytes: u64,
+}
+
+impl Provenance {
+    pub fn new(url: &str, content: &[u8]) -> Self {
+        Self {
+            url: display_url(url).to_string(),
+            fetched_from: None,
+            downloaded_at: chrono::Utc::now().to_rfc3339(),
+            content_type: None,
+            etag: None,
+            last_modified: None,
+            sha256: hex::encode(Sha256::digest(content)),
+            bytes: content.len() as u64,
+        }
+    }
+}
+
+/// File name of the sidecar of an image.
+pub fn sidecar_name(file_name: &str) -> String {
+    format!("{}{}", file_name, SIDECAR_SUFFIX)
+}
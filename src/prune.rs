@@ -0,0 +1,111 @@
+use std::{collections::HashSet, path::Path};
+
+use crate::{
+    checksums::output_files,
+    downloader::{find_markdown, ProcessError},
+    manifest::Manifest,
+    provenance::SIDECAR_SUFFIX,
+    regexp::RegexWrapper,
+    utils::{list_files, local_file_name, relative_link},
+    Opts, PruneOpts,
+};
+
+/// File name a symlink in output dir points to, relative to output dir.
+fn link_target(output_dir: &Path, file_name: &str) -> Option<String> {
+    let path = output_dir.join(file_name);
+    let target = std::fs::read_link(&path).ok()?;
+    relative_link(output_dir, &path.parent()?.join(target)).ok()
+}
+
+/// Delete images in output dir no longer referenced by any markdown file.
+/// Deleted(or to be deleted in dry run) files are printed to stdout.
+pub fn prune_images(opts: &Opts, prune: &PruneOpts) -> Result<(), ProcessError> {
+    // all markdown files are scanned even if changed_since is given
+    let file_list = find_markdown(opts, None)?;
+    let mut referenced = HashSet::new();
+    for path in file_list.iter() {
+        let contents = std::fs::read_to_string(path)?;
+        collect_references(opts, path, &contents, opts.relative_links, &mut referenced)?;
+    }
+    // targets of symlinks saved by `--dedup-strategy symlink` are in use as well
+    let link_targets: HashSet<String> = referenced
+        .iter()
+        .filter_map(|name| link_target(Path::new(&opts.output_dir), name))
+        .collect();
+
+    let mut pruned = HashSet::new();
+    // checksums file of `--checksums` is kept if it is in output dir
+    let files = match &opts.checksums {
+        Some(path) => output_files(Path::new(&opts.output_dir), path)?,
+        None => {
+            let mut files = Vec::new();
+            list_files(Path::new(&opts.output_dir), "", &mut files)?;
+            files
+        }
+    };
+    for file_name in files {
+        // sidecars go with their images
+        let image_name = file_name.strip_suffix(SIDECAR_SUFFIX).unwrap_or(&file_name);
+        if referenced.contains(image_name) || link_targets.contains(image_name) {
+            continue;
+        }
+        let path = Path::new(&opts.output_dir).join(&file_name);
+        println!("{}", path.display());
+        if !prune.dry_run {
+            std::fs::remove_file(path)?;
+        }
+        pruned.insert(file_name);
+    }
+
+    if !prune.dry_run && !pruned.is_empty() {
+        // forget pruned images, or they would be taken as downloaded
+        let mut manifest = Manifest::load(&opts.manifest)?;
+        manifest.images.retain(|_, entry| {
+            local_file_name(&entry.link, &opts.link_prefix)
+                .is_none_or(|file_name| !pruned.contains(file_name))
+        });
+        manifest.save(&opts.manifest)?;
+    }
+    tracing::info!(
+        "{} {} unreferenced images in {}, {} images referenced by {} markdown files",
+        if prune.dry_run { "found" } else { "pruned" },
+        pruned.len(),
+        opts.output_dir,
+        referenced.len(),
+        file_list.len()
+    );
+    Ok(())
+}
+
+/// Collect file names of images under output dir referenced by the markdown file, by links
+/// under link prefix anywhere like srcset, and links relative to the file if asked.
+pub(crate) fn collect_references(
+    opts: &Opts,
+    path: &Path,
+    contents: &str,
+    relative: bool,
+    referenced: &mut HashSet<String>,
+) -> Result<(), ProcessError> {
+    let mut prefixes = vec![opts.link_prefix.clone()];
+    if relative {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let base = relative_link(dir, Path::new(&opts.output_dir))?;
+        if !base.is_empty() {
+            prefixes.push(base);
+        }
+    }
+    for prefix in prefixes.iter() {
+        let mut links = HashSet::new();
+        RegexWrapper::references(prefix).collect_urls(contents, &mut links);
+        referenced.extend(
+            links
+                .iter()
+                .filter_map(|link| local_file_name(link, prefix))
+                .map(str::to_string),
+        );
+    }
+    Ok(())
+}
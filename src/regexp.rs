@@ -1,58 +1,468 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::{Arc, OnceLock},
+};
 
 use regex::Regex;
 
+use crate::{
+    asset::{asset_pattern, AssetType},
+    data::DataFormat,
+    manifest::ImageAttrs,
+    plugin::Plugin,
+    srcset,
+    utils::{ext_to_mime, get_path_ext, normalize_url},
+    Opts,
+};
+
+#[derive(Clone)]
 pub struct RegexWrapper {
     regex: Regex,
+    /// Normalize remote urls, local links are kept as is.
+    normalize: bool,
+    strip_params: Vec<String>,
+    /// Resolve protocol-relative and root-relative links against it, except local links.
+    base_url: Option<(reqwest::Url, String)>,
+    /// Plugins extracting and rewriting links the pattern doesn't cover.
+    plugins: Vec<Arc<dyn Plugin>>,
+    /// Key paths of data file values to capture, `/` separated.
+    data_keys: Vec<glob::Pattern>,
+    /// Urls intentionally left as they are, not reported when they are not replaced.
+    untouched: Arc<HashSet<String>>,
+}
+
+/// Html template and the attributes of urls.
+type Html<'a> = (&'a str, &'a HashMap<String, ImageAttrs>);
+
+struct Replacer<'a>(
+    &'a HashMap<String, String>,
+    &'a RegexWrapper,
+    Option<Html<'a>>,
+    /// Ranges turned off by directives.
+    &'a [Range<usize>],
+);
+
+/// Html comments leaving links as they are, `<!-- sbid:ignore-next -->` for the next link,
+/// and `<!-- sbid:off -->` until `<!-- sbid:on -->` or the end.
+fn directive_regex() -> &'static Regex {
+    static DIRECTIVE: OnceLock<Regex> = OnceLock::new();
+    DIRECTIVE.get_or_init(|| Regex::new(r"<!--\s*sbid:(ignore-next|off|on)\s*-->").unwrap())
+}
+
+fn is_ignored(ignored: &[Range<usize>], pos: usize) -> bool {
+    ignored.iter().any(|range| range.contains(&pos))
 }
 
-struct Replacer<'a>(&'a HashMap<String, String>);
+/// Pattern of markdown images with link starting with `start`. The link is captured
+/// in group `bracketed` if it is written in angle brackets like `<a b.png>`, which may
+/// contain spaces, otherwise in group `bare`, which may contain balanced parentheses
+/// like `a(1).png`.
+fn link_pattern(start: &str) -> String {
+    format!(
+        r"!\[(?P<alt>.*?)\]\(\s*(?:<(?P<bracketed>{start}[^<>\n]*)>|(?P<bare>{start}(?:[^\s()]|\([^\s()]*\))*))\s*.*?\)",
+        start = start
+    )
+}
+
+/// Pattern of `url(...)` in stylesheets and inline styles with link starting with `start`,
+/// captured in group `css`.
+fn css_pattern(start: &str) -> String {
+    format!(
+        r#"(?i:url)\(\s*["']?(?P<css>{start}[^"'()\s]*)"#,
+        start = start
+    )
+}
+
+/// Get the captured link and whether it is written as is, in angle brackets or an html
+/// attribute.
+fn captured_link<'t>(caps: &regex::Captures<'t>) -> (regex::Match<'t>, bool) {
+    let as_is = ["bracketed", "src", "css"];
+    if let Some(m) = as_is.iter().find_map(|name| caps.name(name)) {
+        return (m, true);
+    }
+    (
+        caps.name("bare").or_else(|| caps.name("asset")).unwrap(),
+        false,
+    )
+}
+
+/// Wrap the link in angle brackets if it can not be written bare in markdown.
+fn escape_link(link: &str) -> String {
+    let mut depth = 0i32;
+    let balanced = link.chars().all(|c| {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => (),
+        }
+        depth >= 0
+    }) && depth == 0;
+    if balanced && !link.contains(char::is_whitespace) {
+        link.to_string()
+    } else {
+        format!("<{}>", link)
+    }
+}
 
 impl Default for RegexWrapper {
     fn default() -> Self {
-        Self::new()
+        Self::new(false)
     }
 }
 
 impl RegexWrapper {
-    pub fn new() -> Self {
-        let regex = Regex::new(r"!\[.*?\]\((http[^\s)]*)\s*.*?\)").unwrap();
-        Self { regex }
+    /// Create the wrapper, `data:` URIs will also be captured if `extract_data_uri` is set.
+    pub fn new(extract_data_uri: bool) -> Self {
+        Self::remote(extract_data_uri, false, &[], false)
+    }
+
+    /// Create the wrapper capturing remote urls with options.
+    pub fn from_opts(opts: &Opts) -> Self {
+        let mut wrapper = Self::remote(
+            opts.extract_data_uri,
+            opts.base_url.is_some(),
+            &opts.asset_types,
+            opts.css,
+        );
+        wrapper.strip_params = opts.strip_params.clone();
+        wrapper.base_url = opts
+            .base_url
+            .clone()
+            .map(|base| (base, opts.link_prefix.clone()));
+        wrapper.plugins = opts.plugins.iter().map(|p| p.0.clone()).collect();
+        wrapper.plugins.extend(
+            opts.capture
+                .iter()
+                .map(|pattern| Arc::new(pattern.clone()) as Arc<dyn Plugin>),
+        );
+        wrapper.data_keys = opts
+            .data_keys
+            .iter()
+            .filter_map(|key| match glob::Pattern::new(&key.replace('.', "/")) {
+                Ok(pattern) => Some(pattern),
+                Err(e) => {
+                    tracing::warn!("ignoring invalid data key {}: {}", key, e);
+                    None
+                }
+            })
+            .collect();
+        wrapper
+    }
+
+    fn remote(extract_data_uri: bool, relative: bool, assets: &[AssetType], css: bool) -> Self {
+        let start = match (extract_data_uri, relative) {
+            (false, false) => "http",
+            (true, false) => "(?:http|data:image/)",
+            (false, true) => "(?:http|/)",
+            (true, true) => "(?:http|/|data:image/)",
+        };
+        let mut pattern = link_pattern(start);
+        if !assets.is_empty() {
+            pattern = format!("{}|{}", pattern, asset_pattern(start, assets));
+        }
+        if css {
+            pattern = format!("{}|{}", pattern, css_pattern(start));
+        }
+        Self {
+            regex: Regex::new(&pattern).unwrap(),
+            normalize: true,
+            strip_params: Vec::new(),
+            base_url: None,
+            plugins: Vec::new(),
+            data_keys: Vec::new(),
+            untouched: Arc::default(),
+        }
+    }
+
+    /// Create the wrapper capturing links starting with prefix anywhere, including
+    /// those in html like `srcset` attributes, not only markdown images.
+    pub fn references(prefix: &str) -> Self {
+        let pattern = format!(r#"(?P<bracketed>{}[^\s"'<>(),]*)"#, regex::escape(prefix));
+        Self {
+            regex: Regex::new(&pattern).unwrap(),
+            normalize: false,
+            strip_params: Vec::new(),
+            base_url: None,
+            plugins: Vec::new(),
+            data_keys: Vec::new(),
+            untouched: Arc::default(),
+        }
     }
 
-    pub fn collect_urls(&self, contents: String, hashset: &mut HashSet<String>) {
-        let matches = self.regex.captures_iter(&contents);
-        for mat in matches {
-            let m = mat.get(1).unwrap();
-            hashset.insert(m.as_str().to_string());
+    /// Create the wrapper capturing links starting with any of the prefixes anywhere like
+    /// `references`, or in angle brackets which may contain spaces. Links must start after
+    /// a delimiter, so `/images/a.png` is not found in `../images/a.png`.
+    pub fn local_links(prefixes: &[&str]) -> Self {
+        let prefixes: Vec<String> = prefixes.iter().map(|p| regex::escape(p)).collect();
+        let pattern = format!(
+            r#"(?m)<(?P<bracketed>(?:{start})[^<>\n]*)>|(?:^|[\s"'<>(),=\[])(?P<bare>(?:{start})[^\s"'<>(),]*)"#,
+            start = prefixes.join("|")
+        );
+        Self {
+            regex: Regex::new(&pattern).unwrap(),
+            normalize: false,
+            strip_params: Vec::new(),
+            base_url: None,
+            plugins: Vec::new(),
+            data_keys: Vec::new(),
+            untouched: Arc::default(),
         }
     }
 
-    pub fn replace_urls(
+    /// Create the wrapper capturing local links starting with prefix.
+    pub fn with_prefix(prefix: &str) -> Self {
+        let regex = Regex::new(&link_pattern(&regex::escape(prefix))).unwrap();
+        Self {
+            regex,
+            normalize: false,
+            strip_params: Vec::new(),
+            base_url: None,
+            plugins: Vec::new(),
+            data_keys: Vec::new(),
+            untouched: Arc::default(),
+        }
+    }
+
+    /// Leave the urls as they are without reporting them.
+    pub fn leave_untouched(&mut self, urls: HashSet<String>) {
+        self.untouched = Arc::new(urls);
+    }
+
+    /// Key of the url, so the same image written differently is downloaded once.
+    /// Return None for local links captured as root-relative ones.
+    pub fn key(&self, url: &str) -> Option<String> {
+        if !self.normalize {
+            return Some(url.to_string());
+        }
+        match &self.base_url {
+            Some((base, local_prefix)) if url.starts_with('/') => {
+                if !url.starts_with("//") && url.starts_with(local_prefix.as_str()) {
+                    return None;
+                }
+                let resolved = base.join(url).ok()?;
+                Some(normalize_url(resolved.as_str(), &self.strip_params))
+            }
+            _ => Some(normalize_url(url, &self.strip_params)),
+        }
+    }
+
+    /// Ranges of contents turned off by directives. A range of `ignore-next` ends after the
+    /// first link following it, found by the pattern or plugins.
+    fn ignored_ranges(&self, contents: &str) -> Vec<Range<usize>> {
+        if !contents.contains("sbid:") {
+            return Vec::new();
+        }
+        let mut ranges = Vec::new();
+        let mut off = None;
+        let mut plugin_ranges = None;
+        for caps in directive_regex().captures_iter(contents) {
+            let directive = caps.get(0).unwrap();
+            match (&caps[1], off) {
+                ("off", None) => off = Some(directive.end()),
+                ("on", Some(start)) => {
+                    ranges.push(start..directive.start());
+                    off = None;
+                }
+                ("ignore-next", None) => {
+                    let start = directive.end();
+                    let plugin_ranges = plugin_ranges.get_or_insert_with(|| {
+                        self.plugins
+                            .iter()
+                            .flat_map(|p| p.extract(contents))
+                            .collect::<Vec<_>>()
+                    });
+                    let next = self
+                        .regex
+                        .find_at(contents, start)
+                        .map(|m| m.range())
+                        .into_iter()
+                        .chain(plugin_ranges.iter().filter(|r| r.start >= start).cloned())
+                        .min_by_key(|r| r.start);
+                    if let Some(next) = next {
+                        ranges.push(start..next.end);
+                    }
+                }
+                _ => (),
+            }
+        }
+        if let Some(start) = off {
+            ranges.push(start..contents.len());
+        }
+        ranges
+    }
+
+    pub fn collect_urls(&self, contents: &str, hashset: &mut HashSet<String>) {
+        let ignored = self.ignored_ranges(contents);
+        let matches = self.regex.captures_iter(contents);
+        for mat in matches.filter(|mat| !is_ignored(&ignored, mat.get(0).unwrap().start())) {
+            let (m, _) = captured_link(&mat);
+            if let Some(key) = self.key(m.as_str()) {
+                hashset.insert(key);
+            }
+        }
+        for plugin in self.plugins.iter() {
+            for range in plugin.extract(contents) {
+                if is_ignored(&ignored, range.start) {
+                    continue;
+                }
+                if let Some(key) = self.key(&contents[range]) {
+                    hashset.insert(key);
+                }
+            }
+        }
+    }
+
+    /// Remote urls in values of the data file under key paths, or with image extensions if
+    /// no key path is given, and the ranges of them.
+    fn data_urls(&self, format: DataFormat, contents: &str) -> Vec<(String, Range<usize>)> {
+        const OPTIONS: glob::MatchOptions = glob::MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        format
+            .scan(contents)
+            .into_iter()
+            .filter(|value| {
+                let text = &contents[value.range.clone()];
+                if !text.starts_with("http://") && !text.starts_with("https://") {
+                    return false;
+                }
+                match self.data_keys.is_empty() {
+                    true => get_path_ext(text).is_some_and(|ext| ext_to_mime(ext).is_some()),
+                    false => self
+                        .data_keys
+                        .iter()
+                        .any(|p| p.matches_with(&value.path, OPTIONS)),
+                }
+            })
+            .filter_map(|value| Some((self.key(&contents[value.range.clone()])?, value.range)))
+            .collect()
+    }
+
+    pub fn collect_data_urls(
         &self,
-        contents: String,
+        format: DataFormat,
+        contents: &str,
+        hashset: &mut HashSet<String>,
+    ) {
+        hashset.extend(
+            self.data_urls(format, contents)
+                .into_iter()
+                .map(|(key, _)| key),
+        );
+    }
+
+    /// Replace urls in values of the data file, the rest is kept as is.
+    pub fn replace_data_urls(
+        &self,
+        format: DataFormat,
+        contents: &str,
+        mapping: &HashMap<String, String>,
+    ) -> String {
+        let mut replaced = String::with_capacity(contents.len());
+        let mut last = 0;
+        for (key, range) in self.data_urls(format, contents) {
+            if let Some(link) = mapping.get(&key) {
+                replaced.push_str(&contents[last..range.start]);
+                replaced.push_str(link);
+                last = range.end;
+            }
+        }
+        replaced.push_str(&contents[last..]);
+        replaced
+    }
+
+    /// Replace urls extracted by plugins, in turn after the pattern.
+    fn replace_plugins(&self, mut contents: String, mapping: &HashMap<String, String>) -> String {
+        for plugin in self.plugins.iter() {
+            let ignored = self.ignored_ranges(&contents);
+            let ranges = plugin.extract(&contents);
+            let mut replaced = String::with_capacity(contents.len());
+            let mut last = 0;
+            for range in ranges
+                .into_iter()
+                .filter(|r| !is_ignored(&ignored, r.start))
+            {
+                let url = &contents[range.clone()];
+                let link = match self.key(url).and_then(|key| mapping.get(&key)) {
+                    Some(link) => link,
+                    None => continue,
+                };
+                replaced.push_str(&contents[last..range.start]);
+                replaced.push_str(&plugin.rewrite(url, link));
+                last = range.end;
+            }
+            replaced.push_str(&contents[last..]);
+            contents = replaced;
+        }
+        contents
+    }
+
+    pub fn replace_urls(&self, contents: &str, mapping: &HashMap<String, String>) -> String {
+        let ignored = self.ignored_ranges(contents);
+        let replacer = Replacer(mapping, self, None, &ignored);
+        let contents = self.regex.replace_all(contents, replacer).into_owned();
+        self.replace_plugins(contents, mapping)
+    }
+
+    /// Replace urls with srcset by rendering the whole image with html template,
+    /// others are replaced as replace_urls does.
+    pub fn replace_html(
+        &self,
+        contents: &str,
         mapping: &HashMap<String, String>,
+        template: &str,
+        attrs: &HashMap<String, ImageAttrs>,
     ) -> String {
-        let replacer = Replacer(mapping);
-        self.regex.replace_all(&contents, replacer).to_string()
+        let ignored = self.ignored_ranges(contents);
+        let replacer = Replacer(mapping, self, Some((template, attrs)), &ignored);
+        let contents = self.regex.replace_all(contents, replacer).into_owned();
+        self.replace_plugins(contents, mapping)
     }
 }
 
 impl<'a> regex::Replacer for Replacer<'a> {
     fn replace_append(&mut self, caps: &regex::Captures<'_>, dst: &mut String) {
         let base = caps.get(0).unwrap();
-        let replaced = caps.get(1).unwrap();
+        if is_ignored(self.3, base.start()) {
+            dst.push_str(base.as_str());
+            return;
+        }
+        let (replaced, bracketed) = captured_link(caps);
 
-        match self.0.get(replaced.as_str()) {
-            Some(r) => {
+        let key = match self.1.key(replaced.as_str()) {
+            Some(key) => key,
+            None => {
+                dst.push_str(base.as_str());
+                return;
+            }
+        };
+        let html = self.2.and_then(|(template, attrs)| {
+            let attrs = attrs.get(&key).filter(|a| a.srcset.is_some())?;
+            Some((template, attrs))
+        });
+        match (self.0.get(&key), html) {
+            (Some(r), Some((template, attrs))) => {
+                let alt = caps.name("alt").map_or("", |m| m.as_str());
+                dst.push_str(&srcset::render(template, r, alt, attrs));
+            }
+            (Some(r), None) => {
                 dst.push_str(&base.as_str()[..replaced.start() - base.start()]);
-                dst.push_str(r);
+                if bracketed {
+                    dst.push_str(r);
+                } else {
+                    dst.push_str(&escape_link(r));
+                }
                 dst.push_str(&base.as_str()[replaced.end() - base.start()..]);
             }
-            None => {
+            (None, _) => {
                 // we will keep the original link
                 dst.push_str(base.as_str());
-                tracing::error!("replacing {} failed", replaced.as_str());
+                if !self.1.untouched.contains(&key) {
+                    tracing::error!("replacing {} failed", replaced.as_str());
+                }
             }
         }
     }
@@ -60,11 +470,235 @@ impl<'a> regex::Replacer for Replacer<'a> {
 
 #[cfg(test)]
 mod tests {
+    use super::RegexWrapper;
+    use crate::Opts;
+    use clap::Parser;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_collect_data_uri() {
+        let contents = "![a](https://example.com/a.png) ![b](data:image/png;base64,iVBORw0KGgo=)";
+
+        let mut set = HashSet::new();
+        RegexWrapper::new(false).collect_urls(contents, &mut set);
+        assert_eq!(set.len(), 1);
+
+        let mut set = HashSet::new();
+        RegexWrapper::new(true).collect_urls(contents, &mut set);
+        assert_eq!(set.len(), 2);
+        assert!(set.contains("data:image/png;base64,iVBORw0KGgo="));
+    }
+
+    #[test]
+    fn test_collect_prefix() {
+        let contents = "![a](/images/a.png) ![b](https://example.com/b.png) ![c](/other/c.png)";
+        let mut set = HashSet::new();
+        RegexWrapper::with_prefix("/images").collect_urls(contents, &mut set);
+        assert_eq!(set.len(), 1);
+        assert!(set.contains("/images/a.png"));
+    }
+
+    #[test]
+    fn test_collect_normalized() {
+        let contents = "![a](HTTPS://Example.com:443/a.png?utm_source=x&id=1) \
+            ![b](https://example.com/a.png?id=1&ref=y)";
+        let opts = Opts::parse_from(["image-downloader", "--strip-params", "utm_*,ref"]);
+        let mut set = HashSet::new();
+        RegexWrapper::from_opts(&opts).collect_urls(contents, &mut set);
+        assert_eq!(set.len(), 1);
+        assert!(set.contains("https://example.com/a.png?id=1"));
+    }
+
+    #[test]
+    fn test_collect_special_chars() {
+        let contents =
+            "![a](https://example.com/a(1).png \"t\") ![b](<https://example.com/b c.png>) \
+            ![c](https://例子.com/%E5%9B%BE.png)";
+        let mut set = HashSet::new();
+        RegexWrapper::new(false).collect_urls(contents, &mut set);
+        assert_eq!(set.len(), 3);
+        assert!(set.contains("https://example.com/a(1).png"));
+        assert!(set.contains("https://example.com/b%20c.png"));
+        assert!(set.contains("https://xn--fsqu00a.com/%E5%9B%BE.png"));
+    }
+
+    #[test]
+    fn test_replace_escaped() {
+        let contents = "![a](https://example.com/a(1).png) ![b](<https://example.com/b c.png>)";
+        let mapping = [
+            ("https://example.com/a(1).png", "/my images/a.png"),
+            ("https://example.com/b%20c.png", "/my images/b.png"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let replaced = RegexWrapper::default().replace_urls(contents, &mapping);
+        assert_eq!(
+            replaced,
+            "![a](</my images/a.png>) ![b](</my images/b.png>)"
+        );
+    }
+
+    #[test]
+    fn test_collect_assets() {
+        use std::collections::HashMap;
+
+        let contents =
+            "[demo](https://example.com/a.MP4 \"t\") [page](https://example.com/b.html) \
+            [![cover](https://example.com/c.png)](https://example.com/d.pdf?v=1) \
+            <video controls src=\"https://example.com/e\"></video> \
+            <audio src='https://example.com/g.mp3'></audio> \
+            <picture><source srcset=\"https://example.com/f.webp\"></picture>";
+        let opts = Opts::parse_from(["image-downloader", "--asset-types", "mp4,pdf"]);
+        let regex = RegexWrapper::from_opts(&opts);
+        let mut set = HashSet::new();
+        regex.collect_urls(contents, &mut set);
+        assert_eq!(set.len(), 5);
+        assert!(set.contains("https://example.com/a.MP4"));
+        assert!(set.contains("https://example.com/d.pdf?v=1"));
+        assert!(set.contains("https://example.com/e"));
+
+        let mapping: HashMap<_, _> = set
+            .iter()
+            .map(|url| (url.clone(), url.replace("https://example.com", "/assets")))
+            .collect();
+        let replaced = regex.replace_urls(contents, &mapping);
+        assert!(
+            replaced.starts_with("[demo](/assets/a.MP4 \"t\") [page](https://example.com/b.html)")
+        );
+        assert!(replaced.contains("[![cover](/assets/c.png)](/assets/d.pdf?v=1)"));
+        assert!(replaced.contains("<video controls src=\"/assets/e\">"));
+        assert!(replaced.contains("<audio src='/assets/g.mp3'>"));
+    }
+
+    #[test]
+    fn test_collect_css() {
+        let contents = r#"<div style="background: URL('https://example.com/a.png')"></div>
+            .b { background-image: url(https://example.com/b.png); }
+            .c { src: url("https://example.com/c.woff2") format("woff2"); }"#;
+        let opts = Opts::parse_from(["image-downloader", "--css"]);
+        let regex = RegexWrapper::from_opts(&opts);
+        let mut set = HashSet::new();
+        regex.collect_urls(contents, &mut set);
+        assert_eq!(set.len(), 3);
+        assert!(set.contains("https://example.com/b.png"));
+
+        let mapping = set
+            .iter()
+            .map(|url| (url.clone(), url.replace("https://example.com", "/images")))
+            .collect();
+        let replaced = regex.replace_urls(contents, &mapping);
+        assert!(replaced.contains("URL('/images/a.png')"));
+        assert!(replaced.contains("url(/images/b.png)"));
+    }
+
+    #[test]
+    fn test_collect_relative() {
+        let contents = "![a](//cdn.example.com/a.png) ![b](/uploads/b.png) ![c](/images/c.png)";
+        let opts = Opts::parse_from(["image-downloader", "--base-url", "https://old.example.com"]);
+        let mut set = HashSet::new();
+        RegexWrapper::from_opts(&opts).collect_urls(contents, &mut set);
+        assert_eq!(set.len(), 2);
+        assert!(set.contains("https://cdn.example.com/a.png"));
+        assert!(set.contains("https://old.example.com/uploads/b.png"));
+    }
+
+    #[test]
+    fn test_replace_html() {
+        use crate::manifest::ImageAttrs;
+        use std::collections::HashMap;
+
+        let contents = "![a \"b\"](https://example.com/a.png) ![c](https://example.com/c.gif)";
+        let mapping: HashMap<_, _> = [
+            ("https://example.com/a.png", "/images/a.png"),
+            ("https://example.com/c.gif", "/images/c.gif"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let attrs = ImageAttrs {
+            srcset: Some("/images/a-480w.png 480w, /images/a.png 960w".to_string()),
+            width: Some(960),
+            height: Some(480),
+            ..Default::default()
+        };
+        let attrs = [("https://example.com/a.png".to_string(), attrs)]
+            .into_iter()
+            .collect();
+        let replaced = RegexWrapper::default().replace_html(
+            contents,
+            &mapping,
+            crate::srcset::DEFAULT_TEMPLATE,
+            &attrs,
+        );
+        assert_eq!(
+            replaced,
+            "<img src=\"/images/a.png\" srcset=\"/images/a-480w.png 480w, /images/a.png 960w\" \
+            width=\"960\" height=\"480\" alt=\"a &quot;b&quot;\"> ![c](/images/c.gif)"
+        );
+
+        let mut set = HashSet::new();
+        RegexWrapper::references("/images").collect_urls(&replaced, &mut set);
+        assert_eq!(set.len(), 3);
+        assert!(set.contains("/images/a-480w.png"));
+    }
+
+    #[test]
+    fn test_ignore_directives() {
+        use std::collections::HashMap;
+
+        let contents = "![a](https://a.com/1.png)\n\
+            <!-- sbid:ignore-next --> ![pixel](https://a.com/2.png) ![b](https://a.com/3.png)\n\
+            <!-- sbid:off -->\n![badge](https://a.com/4.png)\n<!-- sbid:on -->\n\
+            <div data-bg=\"https://a.com/5.png\"></div>";
+        let opts = Opts::parse_from([
+            "image-downloader",
+            "--capture",
+            r#"data-bg="(?P<url>http[^"]+)""#,
+        ]);
+        let regex = RegexWrapper::from_opts(&opts);
+        let mut set = HashSet::new();
+        regex.collect_urls(contents, &mut set);
+        let mut urls: Vec<_> = set.iter().map(String::as_str).collect();
+        urls.sort_unstable();
+        assert_eq!(
+            urls,
+            [
+                "https://a.com/1.png",
+                "https://a.com/3.png",
+                "https://a.com/5.png"
+            ]
+        );
+
+        let mapping: HashMap<_, _> = (1..=5)
+            .map(|i| {
+                (
+                    format!("https://a.com/{}.png", i),
+                    format!("/images/{}.png", i),
+                )
+            })
+            .collect();
+        let replaced = regex.replace_urls(contents, &mapping);
+        assert!(replaced.starts_with("![a](/images/1.png)"));
+        assert!(replaced.contains("(https://a.com/2.png) ![b](/images/3.png)"));
+        assert!(replaced.contains("![badge](https://a.com/4.png)"));
+        assert!(replaced.contains("data-bg=\"/images/5.png\""));
+    }
+
+    #[test]
+    fn test_replace() {
+        let regex = RegexWrapper::default();
+        let replaced = regex
+            .regex
+            .replace_all("![a](https://example.com/a.png \"title\")", Replacer);
+        assert_eq!(replaced, "![a](THE_HASH_URL \"title\")");
+    }
+
     struct Replacer;
     impl regex::Replacer for Replacer {
         fn replace_append(&mut self, caps: &regex::Captures<'_>, dst: &mut String) {
             let base = caps.get(0).unwrap();
-            let replaced = caps.get(1).unwrap();
+            let (replaced, _) = super::captured_link(caps);
             println!(
                 "{},{},{},{}",
                 base.start(),
@@ -0,0 +1,352 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    manifest::ImageAttrs,
+    utils::{display_url, write_atomic},
+};
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Downloaded,
+    /// Already saved by a previous run.
+    Cached,
+    /// Replaced since the remote changed, by `--refresh`.
+    Refreshed,
+    /// Skipped since robots.txt of the host disallows it, by `--respect-robots`.
+    Disallowed,
+    /// Left for a later run by `--max-downloads` or `--max-total-bytes`.
+    Deferred,
+    Failed,
+}
+
+/// When the run exits with failure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailOn {
+    /// Only if processing fails as a whole, like rewriting errors.
+    Error,
+    /// Also if any url fails.
+    Any,
+    /// Also if failed urls exceed the percentage.
+    Threshold(f64),
+}
+
+impl FromStr for FailOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "any" => Ok(Self::Any),
+            _ => {
+                let percent = s
+                    .strip_prefix("threshold:")
+                    .map(|p| p.trim_end_matches('%'))
+                    .and_then(|p| p.parse::<f64>().ok())
+                    .filter(|p| (0.0..=100.0).contains(p))
+                    .ok_or_else(|| format!("invalid failure policy {}", s))?;
+                Ok(Self::Threshold(percent))
+            }
+        }
+    }
+}
+
+/// Result of a single url.
+#[derive(Serialize, Debug, Clone)]
+pub struct ImageReport {
+    pub url: String,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The new link written into markdown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    /// Error of the `--hook` command run after saving.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook_error: Option<String>,
+    /// Where it is saved, under output dir for local storages, or the key of remote ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(flatten)]
+    pub attrs: ImageAttrs,
+    /// Markdown files the url is found in.
+    pub files: Vec<PathBuf>,
+    pub bytes: u64,
+    pub duration_ms: u64,
+}
+
+/// Result of a whole run.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Report {
+    pub files: usize,
+    pub images: Vec<ImageReport>,
+    pub duration_ms: u64,
+}
+
+impl Report {
+    /// Whether failed urls fail the run under the policy.
+    pub fn is_failed(&self, policy: FailOn) -> bool {
+        let failed = self
+            .images
+            .iter()
+            .filter(|r| r.outcome == Outcome::Failed)
+            .count();
+        match policy {
+            FailOn::Error => false,
+            FailOn::Any => failed > 0,
+            FailOn::Threshold(percent) => {
+                failed as f64 > self.images.len() as f64 * percent / 100.0
+            }
+        }
+    }
+
+    /// Log summary statistics of the run, failed urls are listed.
+    pub fn log_summary(&self) {
+        let count = |outcome| self.images.iter().filter(|r| r.outcome == outcome).count();
+        let (downloaded, refreshed, skipped, disallowed, deferred, failed) = (
+            count(Outcome::Downloaded),
+            count(Outcome::Refreshed),
+            count(Outcome::Cached),
+            count(Outcome::Disallowed),
+            count(Outcome::Deferred),
+            count(Outcome::Failed),
+        );
+        let bytes: u64 = self.images.iter().map(|r| r.bytes).sum();
+        tracing::info!(
+            "summary: scanned {} files, found {} urls, downloaded {}, refreshed {}, skipped {}, disallowed {}, deferred {}, failed {}, {} bytes in {:.1}s",
+            self.files,
+            self.images.len(),
+            downloaded,
+            refreshed,
+            skipped,
+            disallowed,
+            deferred,
+            failed,
+            bytes,
+            self.duration_ms as f64 / 1000.0
+        );
+        for r in self
+            .images
+            .iter()
+            .filter(|r| r.outcome == Outcome::Disallowed)
+        {
+            tracing::info!("disallowed by robots.txt: {}", r.url);
+        }
+        if deferred > 0 {
+            tracing::info!("{} urls are deferred, run again to continue", deferred);
+        }
+        for r in self.images.iter().filter(|r| r.outcome == Outcome::Failed) {
+            tracing::warn!(
+                "failed: {} ({})",
+                display_url(&r.url),
+                r.error.as_deref().unwrap_or_default()
+            );
+        }
+        let hook_failed: Vec<_> = self
+            .images
+            .iter()
+            .filter_map(|r| Some((&r.url, r.hook_error.as_ref()?)))
+            .collect();
+        if !hook_failed.is_empty() {
+            tracing::warn!("hook failed for {} images", hook_failed.len());
+        }
+        for (url, error) in hook_failed {
+            tracing::warn!("hook failed: {} ({})", display_url(url), error);
+        }
+    }
+
+    /// Write failed urls with their error and source files, used by `--retry-failed`.
+    pub fn write_failures(&self, path: &Path) -> std::io::Result<()> {
+        let failures: Vec<_> = self
+            .images
+            .iter()
+            .filter(|r| r.outcome == Outcome::Failed)
+            .map(|r| Failure {
+                url: r.url.clone(),
+                error: r.error.clone().unwrap_or_default(),
+                files: r.files.clone(),
+            })
+            .collect();
+        tracing::info!(
+            "writing {} failed urls to {}",
+            failures.len(),
+            path.display()
+        );
+        let content = serde_json::to_vec_pretty(&failures)?;
+        write_atomic(path, &content, false)
+    }
+
+    /// Write report to file, or stdout if not given.
+    pub fn emit(&self, format: ReportFormat, output: Option<&PathBuf>) -> std::io::Result<()> {
+        let content = match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+        };
+        match output {
+            Some(path) => std::fs::write(path, content),
+            None => {
+                println!("{}", content);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Write the url to link mapping sorted by url, as csv if the file extension is `.csv`, or
+/// as a json object.
+pub fn write_mapping(path: &Path, mapping: &HashMap<String, String>) -> std::io::Result<()> {
+    let sorted: BTreeMap<_, _> = mapping.iter().collect();
+    let is_csv = path
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("csv"))
+        .unwrap_or(false);
+    let mut content = Vec::new();
+    if is_csv {
+        let quote = |s: &str| match s.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", s.replace('"', "\"\"")),
+            false => s.to_string(),
+        };
+        writeln!(content, "url,link")?;
+        for (url, link) in sorted {
+            writeln!(content, "{},{}", quote(url), quote(link))?;
+        }
+    } else {
+        serde_json::to_writer_pretty(&mut content, &sorted)?;
+    }
+    write_atomic(path, &content, false)
+}
+
+/// Read the url to link mapping written by `write_mapping`.
+pub fn read_mapping(path: &Path) -> std::io::Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(path)?;
+    let is_csv = path
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("csv"))
+        .unwrap_or(false);
+    if !is_csv {
+        return Ok(serde_json::from_str(&content)?);
+    }
+    let invalid = |line: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid mapping line {}", line),
+        )
+    };
+    let mut mapping = HashMap::new();
+    for line in content.lines().skip(1).filter(|l| !l.is_empty()) {
+        match split_csv_line(line).as_slice() {
+            [url, link] => mapping.insert(url.clone(), link.clone()),
+            _ => return Err(invalid(line)),
+        };
+    }
+    Ok(mapping)
+}
+
+/// Split a csv line into fields, which may be quoted with `"` doubled inside.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().expect("fields are never empty");
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(String::new()),
+            (_, c) => field.push(c),
+        }
+    }
+    fields
+}
+
+/// A failed url in failure log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Failure {
+    pub url: String,
+    pub error: String,
+    pub files: Vec<PathBuf>,
+}
+
+/// Read failure log and return failed urls with their source files.
+pub fn read_failures(path: &Path) -> Result<HashMap<String, Vec<PathBuf>>, serde_json::Error> {
+    let f = std::fs::File::open(path).map_err(serde_json::Error::io)?;
+    let failures: Vec<Failure> = serde_json::from_reader(f)?;
+    Ok(failures.into_iter().map(|f| (f.url, f.files)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_fail_on() {
+        use super::{FailOn, ImageReport, Outcome, Report};
+
+        assert_eq!("any".parse(), Ok(FailOn::Any));
+        assert_eq!("threshold:10%".parse(), Ok(FailOn::Threshold(10.0)));
+        assert!("threshold:200%".parse::<FailOn>().is_err());
+        assert!("some".parse::<FailOn>().is_err());
+
+        let image = |outcome| ImageReport {
+            url: String::new(),
+            outcome,
+            error: None,
+            link: None,
+            hook_error: None,
+            path: None,
+            attrs: Default::default(),
+            files: Vec::new(),
+            bytes: 0,
+            duration_ms: 0,
+        };
+        let report = Report {
+            images: vec![
+                image(Outcome::Failed),
+                image(Outcome::Downloaded),
+                image(Outcome::Cached),
+                image(Outcome::Downloaded),
+            ],
+            ..Default::default()
+        };
+        assert!(!report.is_failed(FailOn::Error));
+        assert!(report.is_failed(FailOn::Any));
+        assert!(report.is_failed(FailOn::Threshold(20.0)));
+        assert!(!report.is_failed(FailOn::Threshold(25.0)));
+    }
+
+    #[test]
+    fn test_mapping_roundtrip() {
+        use super::{read_mapping, write_mapping};
+        use std::collections::HashMap;
+
+        let mapping: HashMap<String, String> = [
+            ("https://a.com/a,b.png", "/images/a.png"),
+            ("https://a.com/\"q\".png", "/images/q.png"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        for name in [
+            "image-downloader-mapping.csv",
+            "image-downloader-mapping.json",
+        ] {
+            let path = std::env::temp_dir().join(name);
+            write_mapping(&path, &mapping).unwrap();
+            assert_eq!(read_mapping(&path).unwrap(), mapping);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    header::ACCEPT,
+    Client, Url,
+};
+use serde::Deserialize;
+
+/// A `host:ip` entry overriding DNS of the host, like `--resolve` of curl.
+/// The port in curl style `host:port:ip` is accepted and ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub addr: IpAddr,
+}
+
+impl FromStr for ResolveOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, rest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid resolve entry {}, expect host:ip", s))?;
+        let parse = |ip: &str| ip.trim_start_matches('[').trim_end_matches(']').parse();
+        let addr = parse(rest)
+            .or_else(|e| match rest.split_once(':') {
+                Some((port, ip)) if port.parse::<u16>().is_ok() => parse(ip),
+                _ => Err(e),
+            })
+            .map_err(|_| format!("invalid ip {} in resolve entry", rest))?;
+        if host.is_empty() {
+            return Err(format!("missing host in resolve entry {}", s));
+        }
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            addr,
+        })
+    }
+}
+
+impl ResolveOverride {
+    /// Port is ignored by reqwest, the default port of the scheme is used.
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr, 0)
+    }
+}
+
+/// Resolve hosts with the JSON API of a DNS-over-HTTPS server, like
+/// `https://cloudflare-dns.com/dns-query` or `https://dns.google/resolve`.
+#[derive(Clone)]
+pub struct DohResolver {
+    /// Client using system resolver, to reach the DoH server itself.
+    client: Client,
+    endpoint: String,
+    cache: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+}
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    kind: u16,
+    data: String,
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+impl DohResolver {
+    pub fn new(endpoint: String, client: Client) -> Self {
+        Self {
+            client,
+            endpoint,
+            cache: Default::default(),
+        }
+    }
+
+    async fn query(&self, host: &str, kind: u16) -> Result<Vec<IpAddr>, reqwest::Error> {
+        let body = self
+            .client
+            .get(&self.endpoint)
+            .query(&[("name", host), ("type", &kind.to_string())])
+            .header(ACCEPT, "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let addrs = match serde_json::from_slice::<DohResponse>(&body) {
+            // NOERROR, other status means the name does not resolve
+            Ok(resp) if resp.status == 0 => resp
+                .answer
+                .into_iter()
+                .filter(|a| a.kind == kind)
+                .filter_map(|a| a.data.parse().ok())
+                .collect(),
+            Ok(_) => Vec::new(),
+            Err(e) => {
+                tracing::warn!("invalid DoH response for {}: {}", host, e);
+                Vec::new()
+            }
+        };
+        Ok(addrs)
+    }
+
+    async fn lookup(&self, host: String) -> Result<Vec<IpAddr>, String> {
+        if let Some(addrs) = self.cache.lock().unwrap().get(&host) {
+            return Ok(addrs.clone());
+        }
+        let (v4, v6) = tokio::join!(self.query(&host, TYPE_A), self.query(&host, TYPE_AAAA));
+        let mut addrs = Vec::new();
+        let mut error = None;
+        for ret in [v4, v6] {
+            match ret {
+                Ok(found) => addrs.extend(found),
+                Err(e) => error = Some(e),
+            }
+        }
+        if addrs.is_empty() {
+            return Err(match error {
+                Some(e) => format!("DoH lookup of {} failed: {}", host, e),
+                None => format!("{} not found by DoH", host),
+            });
+        }
+        tracing::debug!("resolved {} to {:?} with DoH", host, addrs);
+        self.cache.lock().unwrap().insert(host, addrs.clone());
+        Ok(addrs)
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let (this, host) = (self.clone(), name.as_str().to_string());
+        Box::pin(async move {
+            let addrs = this.lookup(host).await?;
+            let addrs: Addrs = Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+/// Whether the address is loopback, private, link-local or otherwise not public, those
+/// are refused by `--block-private-ips`.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // "this network", which reaches the host itself on some systems
+                || a == 0
+                // shared address space of carrier-grade NAT
+                || (a == 100 && b & 0xC0 == 64)
+                // benchmarking
+                || (a == 198 && b & 0xFE == 18)
+        }
+        // mapped and compatible ones, where `::` and `::1` end up in 0.0.0.0/8
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip) => is_private(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                // well-known prefix of NAT64 translates to the embedded address
+                if segments[..6] == [0x64, 0xFF9B, 0, 0, 0, 0] {
+                    let [a, b] = segments[6].to_be_bytes();
+                    let [c, d] = segments[7].to_be_bytes();
+                    return is_private(IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+                }
+                ip.is_multicast()
+                    // unique local and link-local
+                    || segments[0] & 0xFE00 == 0xFC00
+                    || segments[0] & 0xFFC0 == 0xFE80
+            }
+        },
+    }
+}
+
+/// The private address the url points to directly, which skips resolvers.
+pub fn private_host(url: &Url) -> Option<IpAddr> {
+    let host = url.host_str()?;
+    let ip: IpAddr = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()?;
+    is_private(ip).then_some(ip)
+}
+
+/// Resolve hosts with DoH if given, or the system resolver, and drop private addresses.
+#[derive(Clone)]
+pub struct PublicResolver {
+    doh: Option<DohResolver>,
+}
+
+impl PublicResolver {
+    pub fn new(doh: Option<DohResolver>) -> Self {
+        Self { doh }
+    }
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let (doh, host) = (self.doh.clone(), name.as_str().to_string());
+        Box::pin(async move {
+            let addrs: Vec<IpAddr> = match doh {
+                Some(doh) => doh.lookup(host.clone()).await?,
+                None => tokio::net::lookup_host((host.as_str(), 0))
+                    .await?
+                    .map(|a| a.ip())
+                    .collect(),
+            };
+            let public: Vec<_> = addrs.into_iter().filter(|ip| !is_private(*ip)).collect();
+            if public.is_empty() {
+                return Err(format!("{} only resolves to private addresses, blocked", host).into());
+            }
+            let addrs: Addrs = Box::new(public.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_resolve() {
+        use super::ResolveOverride;
+
+        let entry: ResolveOverride = "img.example.com:1.2.3.4".parse().unwrap();
+        assert_eq!(entry.host, "img.example.com");
+        assert_eq!(entry.addr.to_string(), "1.2.3.4");
+        let entry: ResolveOverride = "Example.com:443:[::1]".parse().unwrap();
+        assert_eq!(entry.host, "example.com");
+        assert_eq!(entry.addr.to_string(), "::1");
+        assert!("example.com".parse::<ResolveOverride>().is_err());
+        let entry: ResolveOverride = "example.com:1::2".parse().unwrap();
+        assert_eq!(entry.addr.to_string(), "1::2");
+        assert!("example.com:host".parse::<ResolveOverride>().is_err());
+    }
+
+    #[test]
+    fn test_private_host() {
+        use super::private_host;
+        use reqwest::Url;
+
+        let private = |url: &str| private_host(&Url::parse(url).unwrap()).is_some();
+        assert!(private("http://127.0.0.1:8080/a.png"));
+        assert!(private("http://10.1.2.3/a.png"));
+        assert!(private("http://169.254.169.254/latest/meta-data"));
+        assert!(private("http://100.64.0.1/a.png"));
+        assert!(private("http://[::1]/a.png"));
+        assert!(private("http://[fd00::1]/a.png"));
+        assert!(private("http://[::ffff:192.168.1.1]/a.png"));
+        assert!(private("http://0.0.0.0/a.png"));
+        assert!(private("http://0.1.2.3/a.png"));
+        assert!(private("http://224.0.0.1/a.png"));
+        assert!(private("http://198.19.0.1/a.png"));
+        assert!(private("http://[::]/a.png"));
+        assert!(private("http://[::127.0.0.1]/a.png"));
+        assert!(private("http://[64:ff9b::10.0.0.1]/a.png"));
+        assert!(private("http://[ff02::1]/a.png"));
+        assert!(!private("http://[64:ff9b::8.8.8.8]/a.png"));
+        assert!(!private("http://198.20.0.1/a.png"));
+        assert!(!private("http://8.8.8.8/a.png"));
+        assert!(!private("http://[2606:4700::1111]/a.png"));
+        // host names are checked when resolved
+        assert!(!private("http://localhost/a.png"));
+    }
+}
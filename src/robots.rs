@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use reqwest::{Client, StatusCode, Url};
+use tokio::sync::OnceCell;
+
+/// Allow and disallow rules of robots.txt in the group for our user agent.
+#[derive(Debug, Default)]
+pub struct Rules(Vec<Rule>);
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl Rules {
+    /// Parse robots.txt, rules in the group with the longest user agent token found in
+    /// ours are used, or those of `*` if none matches.
+    pub fn parse(contents: &str, user_agent: &str) -> Self {
+        let user_agent = user_agent.to_ascii_lowercase();
+        // specificity of the chosen group, the length of its token
+        let mut best: Option<(usize, Vec<Rule>)> = None;
+        let mut agents: Vec<String> = Vec::new();
+        let mut rules = Vec::new();
+        let mut in_rules = false;
+        let mut flush = |agents: &[String], rules: &[Rule]| {
+            for agent in agents {
+                let specificity = match agent.as_str() {
+                    "*" => 0,
+                    token if user_agent.contains(token) => token.len(),
+                    _ => continue,
+                };
+                match &mut best {
+                    Some((current, chosen)) if *current == specificity => {
+                        chosen.extend_from_slice(rules)
+                    }
+                    Some((current, _)) if *current > specificity => (),
+                    _ => best = Some((specificity, rules.to_vec())),
+                }
+            }
+        };
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+                None => continue,
+            };
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        flush(&agents, &rules);
+                        agents.clear();
+                        rules.clear();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_ascii_lowercase());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // empty disallow allows everything
+                    if !value.is_empty() {
+                        rules.push(Rule {
+                            allow: key == "allow",
+                            pattern: value.to_string(),
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+        flush(&agents, &rules);
+        Self(best.map(|(_, rules)| rules).unwrap_or_default())
+    }
+
+    /// Whether the path with query is allowed, the longest matching rule wins and allow
+    /// wins ties.
+    pub fn is_allowed(&self, path: &str) -> bool {
+        self.0
+            .iter()
+            .filter(|r| matches(&r.pattern, path))
+            .max_by_key(|r| (r.pattern.len(), r.allow))
+            .is_none_or(|r| r.allow)
+    }
+}
+
+/// Match path against a pattern with `*` wildcards and an optional `$` end anchor.
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let mut rest = match path.strip_prefix(parts.next().unwrap_or_default()) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<_> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if anchored && i + 1 == parts.len() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+/// Fetch robots.txt once per origin and check urls against it.
+pub struct Robots {
+    client: Client,
+    user_agent: String,
+    cache: Mutex<HashMap<String, Arc<OnceCell<Rules>>>>,
+}
+
+impl Robots {
+    pub fn new(client: Client, user_agent: String) -> Self {
+        Self {
+            client,
+            user_agent,
+            cache: Default::default(),
+        }
+    }
+
+    pub async fn is_allowed(&self, url: &Url) -> bool {
+        let origin = url.origin().ascii_serialization();
+        let cell = self
+            .cache
+            .lock()
+            .unwrap()
+            .entry(origin.clone())
+            .or_default()
+            .clone();
+        let rules = cell.get_or_init(|| self.fetch(origin)).await;
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        rules.is_allowed(&path)
+    }
+
+    /// Missing robots.txt allows everything, so do unreachable ones.
+    async fn fetch(&self, origin: String) -> Rules {
+        let url = format!("{}/robots.txt", origin);
+        let ret = match self.client.get(&url).send().await {
+            Ok(resp) if resp.status() == StatusCode::OK => resp.text().await,
+            Ok(resp) => {
+                tracing::debug!("{} responded {}, all allowed", url, resp.status());
+                return Rules::default();
+            }
+            Err(e) => Err(e),
+        };
+        match ret {
+            Ok(contents) => Rules::parse(&contents, &self.user_agent),
+            Err(e) => {
+                tracing::warn!("fetching {} with error {}, all allowed", url, e);
+                Rules::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_robots_rules() {
+        use super::Rules;
+
+        let robots = "User-agent: *\n\
+            Disallow: /private/\n\
+            Allow: /private/public*.png$\n\
+            Disallow: /*.gif$\n\
+            \n\
+            User-agent: BadBot\n\
+            Disallow: /\n";
+        let rules = Rules::parse(robots, "Mozilla/5.0 Chrome/94.0");
+        assert!(rules.is_allowed("/a.png"));
+        assert!(!rules.is_allowed("/private/a.png"));
+        assert!(rules.is_allowed("/private/public-1.png"));
+        assert!(!rules.is_allowed("/private/public-1.png?x=1"));
+        assert!(!rules.is_allowed("/x/a.gif"));
+        assert!(rules.is_allowed("/x/a.gif?v=1"));
+
+        let rules = Rules::parse(robots, "Mozilla/5.0 (compatible; BadBot/1.0)");
+        assert!(!rules.is_allowed("/a.png"));
+        assert!(Rules::parse("User-agent: *\nDisallow:\n", "x").is_allowed("/a"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use regex::Regex;
+
+use crate::{
+    downloader::{find_markdown, relativize, ProcessError, RewriteOpts},
+    manifest::Manifest,
+    provenance::sidecar_name,
+    prune::collect_references,
+    regexp::RegexWrapper,
+    report::read_mapping,
+    srcset::{rendered_pattern, unescape},
+    utils::{is_contained, local_file_name},
+    Opts, RollbackOpts,
+};
+
+/// Rewrite local links back to the original urls with the exported mapping, or manifest
+/// if not given, and delete the downloaded files if asked. Links are looked for the way
+/// they are written, so options like `--relative-links` should be the same as downloading.
+pub fn rollback_markdown(opts: &Opts, rollback: &RollbackOpts) -> Result<(), ProcessError> {
+    let mut manifest = Manifest::load(&opts.manifest)?;
+    let mapping = match &opts.mapping {
+        Some(path) => read_mapping(path)?,
+        None => manifest.mapping(),
+    };
+    let reverse = reverse_mapping(&mapping);
+    tracing::info!("rolling back {} links", reverse.len());
+
+    let file_list = find_markdown(opts, None)?;
+    let rewrite_opts = RewriteOpts::from(opts);
+    let rendered = rendered_pattern(&opts.srcset_template);
+    let mut referenced = HashSet::new();
+    for path in file_list.iter() {
+        let contents = std::fs::read_to_string(path)?;
+        let new_contents = rollback_file(
+            opts,
+            &rewrite_opts,
+            path,
+            &contents,
+            (&mapping, &reverse),
+            rendered.as_ref(),
+        )?;
+        rewrite_opts.write(path, &contents, &new_contents)?;
+        // relative links count even without `--relative-links`, which may be left out here
+        collect_references(opts, path, &new_contents, true, &mut referenced)?;
+    }
+    tracing::info!("rewritten all markdown files done");
+
+    if rollback.delete {
+        let file_names: Vec<&str> = reverse
+            .keys()
+            .filter_map(|link| local_file_name(link, &opts.link_prefix))
+            .filter(|name| is_contained(name))
+            .collect();
+        let kept: Vec<&str> = file_names
+            .iter()
+            .copied()
+            .filter(|name| referenced.contains(*name))
+            .collect();
+        if !kept.is_empty() {
+            for name in kept.iter() {
+                tracing::warn!("{} is still referenced, nothing is deleted", name);
+            }
+            return Err(ProcessError::Referenced(kept.len()));
+        }
+        let mut deleted = 0;
+        for file_name in file_names {
+            let path = Path::new(&opts.output_dir).join(file_name);
+            for path in [sidecar_name(&path.to_string_lossy()).into(), path] {
+                match std::fs::remove_file(&path) {
+                    Ok(()) => deleted += 1,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        // forget them, or they would be taken as downloaded
+        manifest.images.retain(|url, _| !mapping.contains_key(url));
+        manifest.save(&opts.manifest)?;
+        tracing::info!("deleted {} files in {}", deleted, opts.output_dir);
+    }
+    Ok(())
+}
+
+/// Links to original urls. Data uris are kept as they are, they can't be written back in
+/// a readable way.
+fn reverse_mapping(mapping: &HashMap<String, String>) -> HashMap<String, String> {
+    mapping
+        .iter()
+        .filter(|(url, _)| !url.starts_with("data:"))
+        .map(|(url, link)| (link.clone(), url.clone()))
+        .collect()
+}
+
+/// Contents of the file with the links written back, including those relative to it and
+/// images rendered with srcset template, which go back to markdown images.
+fn rollback_file(
+    opts: &Opts,
+    rewrite_opts: &RewriteOpts,
+    path: &Path,
+    contents: &str,
+    (mapping, reverse): (&HashMap<String, String>, &HashMap<String, String>),
+    rendered: Option<&Regex>,
+) -> Result<String, ProcessError> {
+    let relative = rewrite_opts.relative_base(path)?;
+    let reverse = match &relative {
+        Some((prefix, base)) => {
+            let (links, _) = relativize(prefix, base, mapping, &HashMap::new());
+            let mut reverse = reverse.clone();
+            reverse.extend(reverse_mapping(&links));
+            Cow::Owned(reverse)
+        }
+        None => Cow::Borrowed(reverse),
+    };
+
+    let contents = match rendered {
+        Some(pattern) => pattern.replace_all(contents, |caps: &regex::Captures| {
+            match reverse.get(&unescape(&caps["src"])) {
+                Some(url) => {
+                    let alt = caps
+                        .name("alt")
+                        .map_or(String::new(), |m| unescape(m.as_str()));
+                    format!("![{}]({})", alt, url)
+                }
+                None => caps[0].to_string(),
+            }
+        }),
+        None => Cow::Borrowed(contents),
+    };
+
+    let mut prefixes = vec![opts.link_prefix.as_str()];
+    prefixes.extend(
+        relative
+            .as_ref()
+            .map(|(_, base)| base.as_str())
+            .filter(|b| !b.is_empty()),
+    );
+    let mut regex = RegexWrapper::local_links(&prefixes);
+    // others under the prefixes are not ours to roll back, like srcset variants
+    let mut unknown = HashSet::new();
+    regex.collect_urls(&contents, &mut unknown);
+    unknown.retain(|link| !reverse.contains_key(link));
+    regex.leave_untouched(unknown);
+    Ok(regex.replace_urls(&contents, &reverse))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_rollback_after_download() {
+        use super::rollback_markdown;
+        use crate::{
+            downloader::{find_markdown, rewrite_file, ProcessError, RewriteOpts},
+            manifest::{ImageAttrs, Manifest},
+            regexp::RegexWrapper,
+            Opts, RollbackOpts,
+        };
+        use clap::Parser;
+        use std::collections::HashMap;
+
+        let dir = std::env::temp_dir().join("image-downloader-rollback");
+        let _ = std::fs::remove_dir_all(&dir);
+        let (content, data, images) = (dir.join("content"), dir.join("data"), dir.join("images"));
+        for dir in [&content, &data, &images] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        let post = "![a \"b\"](https://example.com/a.png)\n\
+            <div style=\"background: url('https://example.com/c.png')\"></div>\n\
+            [demo](https://example.com/d.mp4) ![e](https://example.com/e.png)\n";
+        let site = "{\"logo\": \"https://example.com/c.png\"}";
+        std::fs::write(content.join("post.md"), post).unwrap();
+        std::fs::write(data.join("site.json"), site).unwrap();
+        let mut mapping = HashMap::new();
+        for name in ["a.png", "a-480w.png", "c.png", "d.mp4", "e.png"] {
+            std::fs::write(images.join(name), name).unwrap();
+            if name != "a-480w.png" {
+                let url = format!("https://example.com/{}", name);
+                mapping.insert(url, format!("/images/{}", name));
+            }
+        }
+        let attrs = ImageAttrs {
+            srcset: Some("/images/a-480w.png 480w, /images/a.png 960w".to_string()),
+            width: Some(960),
+            ..Default::default()
+        };
+        let attrs = HashMap::from([("https://example.com/a.png".to_string(), attrs)]);
+
+        let manifest = dir.join("m.json");
+        let args = format!(
+            "image-downloader -i {} -o {} --manifest {} --data-dir {} --css --asset-types mp4 \
+            --srcset 480",
+            content.display(),
+            images.display(),
+            manifest.display(),
+            data.display()
+        );
+        let args: Vec<&str> = args.split_whitespace().collect();
+        let opts = Opts::parse_from(args.iter().chain(&["--relative-links"]));
+        let regex = RegexWrapper::from_opts(&opts);
+        let rewrite_opts = RewriteOpts::from(&opts);
+        for file in find_markdown(&opts, None).unwrap() {
+            let contents = std::fs::read_to_string(&file).unwrap();
+            rewrite_file(&file, contents, &regex, &mapping, &attrs, &rewrite_opts).unwrap();
+        }
+        let rewritten = std::fs::read_to_string(content.join("post.md")).unwrap();
+        assert!(rewritten.contains("srcset=\"../images/a-480w.png 480w"));
+        assert!(rewritten.contains("url('../images/c.png')"));
+        let mut recorded = Manifest::default();
+        recorded.update(&mapping, &attrs);
+        recorded.save(&manifest).unwrap();
+
+        // relative links are not looked for without the option, so nothing is deleted
+        let delete = RollbackOpts { delete: true };
+        let ret = rollback_markdown(&Opts::parse_from(&args), &delete);
+        assert!(matches!(ret, Err(ProcessError::Referenced(4))));
+        assert!(images.join("a.png").exists());
+
+        rollback_markdown(&opts, &delete).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(content.join("post.md")).unwrap(),
+            post
+        );
+        assert_eq!(
+            std::fs::read_to_string(data.join("site.json")).unwrap(),
+            site
+        );
+        assert!(!images.join("a.png").exists());
+        // variants are not in the mapping, prune them later
+        assert!(images.join("a-480w.png").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::FutureExt;
+use hyper::{
+    body::HttpBody,
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    downloader::{build_client, process_document, process_files, ProcessError},
+    report::Report,
+    shutdown::Shutdown,
+    storage::{build_storage, Storage},
+    Opts,
+};
+
+/// Larger request bodies are rejected.
+const MAX_BODY_BYTES: usize = 16 << 20;
+
+/// Json body of `POST /process`, a markdown file under input dirs to process in place, or
+/// a markdown document to rewrite and return. Other content types are taken as markdown.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ProcessRequest {
+    path: Option<PathBuf>,
+    markdown: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ProcessResponse {
+    /// The rewritten document if one is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    markdown: Option<String>,
+    #[serde(flatten)]
+    report: Report,
+}
+
+struct State {
+    opts: Opts,
+    storage: Arc<dyn Storage>,
+    /// Input dirs canonicalized, paths out of them are refused.
+    roots: Vec<PathBuf>,
+    /// Requests are processed one by one, as they share the manifest.
+    running: Mutex<()>,
+    shutdown: Shutdown,
+}
+
+/// Serve the HTTP API until shutdown, the client and storage are kept across requests.
+pub async fn serve(
+    mut opts: Opts,
+    addr: SocketAddr,
+    shutdown: Shutdown,
+) -> Result<(), ProcessError> {
+    opts.client = Some(build_client(&opts));
+    let storage = build_storage(opts.storage, &opts)?;
+    let roots = opts
+        .input
+        .iter()
+        .map(std::fs::canonicalize)
+        .collect::<Result<_, _>>()?;
+    let state = Arc::new(State {
+        opts,
+        storage,
+        roots,
+        running: Mutex::new(()),
+        shutdown: shutdown.clone(),
+    });
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(state.clone(), req).map(Ok::<_, Infallible>).boxed()
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    tracing::info!("serving on http://{}", server.local_addr());
+    let mut shutdown = shutdown;
+    server
+        .with_graceful_shutdown(async move { shutdown.triggered().await })
+        .await?;
+    Ok(())
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/process") => (),
+        (_, "/process") => return error(StatusCode::METHOD_NOT_ALLOWED, "only POST is allowed"),
+        _ => return error(StatusCode::NOT_FOUND, "not found"),
+    }
+    let is_json = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let body = match read_body(req.into_body()).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let request = match is_json {
+        true => match serde_json::from_slice::<ProcessRequest>(&body) {
+            Ok(request) => request,
+            Err(e) => return error(StatusCode::BAD_REQUEST, &e.to_string()),
+        },
+        false => match String::from_utf8(body) {
+            Ok(markdown) => ProcessRequest {
+                path: None,
+                markdown: Some(markdown),
+            },
+            Err(_) => return error(StatusCode::BAD_REQUEST, "markdown is not utf-8"),
+        },
+    };
+
+    let _running = state.running.lock().await;
+    let ret = match request {
+        ProcessRequest {
+            path: Some(path),
+            markdown: None,
+        } => {
+            let path = match state.resolve(&path) {
+                Some(path) => path,
+                None => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "path is not a file under input dirs",
+                    )
+                }
+            };
+            tracing::info!("processing {}", path.display());
+            let shutdown = state.shutdown.clone();
+            process_files(&state.opts, state.storage.clone(), &[path], shutdown)
+                .await
+                .map(|report| ProcessResponse {
+                    markdown: None,
+                    report,
+                })
+        }
+        ProcessRequest {
+            path: None,
+            markdown: Some(markdown),
+        } => {
+            let shutdown = state.shutdown.clone();
+            process_document(&state.opts, state.storage.clone(), markdown, shutdown)
+                .await
+                .map(|(markdown, report)| ProcessResponse {
+                    markdown: Some(markdown),
+                    report,
+                })
+        }
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "exactly one of path and markdown is required",
+            )
+        }
+    };
+    match ret {
+        Ok(response) => json(
+            StatusCode::OK,
+            serde_json::to_vec(&response).expect("report is always serializable"),
+        ),
+        Err(e) => {
+            tracing::error!("process request in error: {}", e);
+            error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+impl State {
+    /// Canonical path of the file if it is under one of input dirs.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let path = std::fs::canonicalize(path).ok()?;
+        (path.is_file() && self.roots.iter().any(|root| path.starts_with(root))).then_some(path)
+    }
+}
+
+/// Read the whole body, or the response to reply if it is too large or broken.
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Response<Body>> {
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+        if content.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(error(StatusCode::PAYLOAD_TOO_LARGE, "body is too large"));
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content)
+}
+
+fn json(status: StatusCode, body: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .expect("response is always valid")
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json(
+        status,
+        serde_json::to_vec(&serde_json::json!({ "error": message }))
+            .expect("error is always serializable"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_handle_invalid_requests() {
+        use std::sync::Arc;
+
+        use clap::Parser;
+        use hyper::{Body, Request, StatusCode};
+
+        use super::{handle, State};
+        use crate::{shutdown::Shutdown, storage::build_storage, Opts};
+
+        let opts = Opts::parse_from(["image-downloader", "--input", "/tmp"]);
+        let state = Arc::new(State {
+            storage: build_storage(opts.storage, &opts).unwrap(),
+            opts,
+            roots: vec!["/tmp".into()],
+            running: Default::default(),
+            shutdown: Shutdown::never(),
+        });
+        let post = |body: &str| {
+            Request::post("/process")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let status = |req| {
+            let state = state.clone();
+            async move { handle(state, req).await.status() }
+        };
+        assert_eq!(
+            status(Request::get("/process").body(Body::empty()).unwrap()).await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status(post(r#"{"path": "/etc/passwd"}"#)).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(post(r#"{"url": "x"}"#)).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(status(post("{}")).await, StatusCode::BAD_REQUEST);
+    }
+}
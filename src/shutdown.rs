@@ -0,0 +1,42 @@
+use tokio::sync::watch;
+
+/// Shutdown signal triggered by Ctrl-C.
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Listen to Ctrl-C in background.
+    pub fn listen() -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                tracing::warn!("interrupted, waiting in-flight downloads to finish");
+                let _ = tx.send(true);
+            }
+        });
+        Self { rx }
+    }
+
+    /// Shutdown which is never triggered.
+    #[cfg(test)]
+    pub fn never() -> Self {
+        let (_, rx) = watch::channel(false);
+        Self { rx }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Wait until shutdown is triggered.
+    pub async fn triggered(&mut self) {
+        while !*self.rx.borrow() {
+            if self.rx.changed().await.is_err() {
+                // sender dropped without triggering, wait forever
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
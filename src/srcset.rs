@@ -0,0 +1,175 @@
+use bytes::Bytes;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageFormat};
+use regex::Regex;
+
+use crate::{convert::ConvertError, manifest::ImageAttrs, utils::get_path_ext};
+
+/// Template used if not given.
+pub const DEFAULT_TEMPLATE: &str =
+    r#"<img src="{src}" srcset="{srcset}" width="{width}" height="{height}" alt="{alt}">"#;
+
+/// Generate narrower variants of images for `srcset`.
+#[derive(Debug, Clone)]
+pub struct Srcset {
+    widths: Vec<u32>,
+    /// 0 to 100, used by JPEG and WebP.
+    quality: f32,
+}
+
+/// A resized image.
+pub struct Variant {
+    pub width: u32,
+    pub file_name: String,
+    pub content: Bytes,
+}
+
+impl Srcset {
+    pub fn new(mut widths: Vec<u32>, quality: f32) -> Self {
+        widths.sort_unstable();
+        widths.dedup();
+        Self { widths, quality }
+    }
+
+    /// Resize the image to widths narrower than itself, return its own width and the variants.
+    /// It is slow, so call it in blocking threads.
+    pub fn variants(
+        &self,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<(u32, Vec<Variant>), ConvertError> {
+        let ext = get_path_ext(file_name).unwrap_or_default();
+        let image = image::load_from_memory(content)?;
+        let mut variants = Vec::new();
+        for &width in self.widths.iter().filter(|&&w| w < image.width()) {
+            let resized = image.resize(width, u32::MAX, FilterType::Lanczos3);
+            variants.push(Variant {
+                width,
+                file_name: format!(
+                    "{}-{}w{}",
+                    &file_name[..file_name.len() - ext.len()],
+                    width,
+                    ext
+                ),
+                content: self.encode(&resized, ext)?,
+            });
+        }
+        Ok((image.width(), variants))
+    }
+
+    fn encode(&self, image: &DynamicImage, ext: &str) -> Result<Bytes, ConvertError> {
+        let mut encoded = Vec::new();
+        match ext.to_ascii_lowercase().as_str() {
+            ".webp" => {
+                let rgba = image.to_rgba8();
+                let encoder = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height());
+                let webp = encoder
+                    .encode_simple(false, self.quality)
+                    .map_err(|e| ConvertError::WebP(format!("{:?}", e)))?;
+                encoded.extend_from_slice(&webp);
+            }
+            ".jpg" | ".jpeg" => {
+                let encoder = JpegEncoder::new_with_quality(&mut encoded, self.quality as u8);
+                image.to_rgb8().write_with_encoder(encoder)?;
+            }
+            _ => image.write_to(&mut std::io::Cursor::new(&mut encoded), ImageFormat::Png)?,
+        }
+        Ok(encoded.into())
+    }
+}
+
+/// Render an image with template, `{src}`, `{alt}`, `{srcset}`, `{placeholder}`, `{width}`
+/// and `{height}` are replaced.
+pub fn render(template: &str, src: &str, alt: &str, attrs: &ImageAttrs) -> String {
+    template
+        .replace("{src}", &escape(src))
+        .replace("{alt}", &escape(alt))
+        .replace(
+            "{srcset}",
+            &escape(attrs.srcset.as_deref().unwrap_or_default()),
+        )
+        .replace(
+            "{placeholder}",
+            &escape(attrs.placeholder.as_deref().unwrap_or_default()),
+        )
+        .replace(
+            "{width}",
+            &attrs.width.map_or(String::new(), |w| w.to_string()),
+        )
+        .replace(
+            "{height}",
+            &attrs.height.map_or(String::new(), |h| h.to_string()),
+        )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Reverse of the escaping of values in rendered images.
+pub fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Pattern of images rendered with template, capturing `src` and `alt` of them.
+/// Return None if the template has no `{src}`.
+pub fn rendered_pattern(template: &str) -> Option<Regex> {
+    let placeholder = Regex::new(r"\{(src|alt|srcset|placeholder|width|height)\}").unwrap();
+    let (mut src, mut alt) = (false, false);
+    let mut pattern = String::new();
+    let mut last = 0;
+    for caps in placeholder.captures_iter(template) {
+        let m = caps.get(0).unwrap();
+        pattern.push_str(&regex::escape(&template[last..m.start()]));
+        // values are escaped, and only the first of each name can be captured
+        pattern.push_str(match &caps[1] {
+            "src" if !src => {
+                src = true;
+                r#"(?P<src>[^"<>]*)"#
+            }
+            "alt" if !alt => {
+                alt = true;
+                r#"(?P<alt>[^"<>]*)"#
+            }
+            _ => r#"[^"<>]*"#,
+        });
+        last = m.end();
+    }
+    pattern.push_str(&regex::escape(&template[last..]));
+    src.then(|| Regex::new(&pattern).unwrap())
+}
+
+/// Read width and height from the image header without decoding it.
+pub fn dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    image::ImageReader::new(std::io::Cursor::new(content))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_variants() {
+        use super::Srcset;
+        use image::{ImageFormat, RgbImage};
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbImage::new(100, 50)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let srcset = Srcset::new(vec![80, 20, 200], 80.0);
+        let (width, variants) = srcset.variants("a.png", png.get_ref()).unwrap();
+        assert_eq!(width, 100);
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].file_name, "a-20w.png");
+        assert_eq!(variants[1].file_name, "a-80w.png");
+        assert_eq!(super::dimensions(&variants[1].content), Some((80, 40)));
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use super::{join_link, Storage, StorageError};
+
+/// Save images into the git-lfs object store and write pointer files to output dir.
+pub struct GitLfsStorage {
+    output_dir: PathBuf,
+    link_prefix: String,
+    lfs_dir: PathBuf,
+}
+
+impl GitLfsStorage {
+    /// The git repository is discovered from output_dir.
+    pub fn new(output_dir: &str, link_prefix: &str) -> Result<Self, StorageError> {
+        let output = Command::new("git")
+            .args(["rev-parse", "--absolute-git-dir"])
+            .current_dir(output_dir)
+            .output()?;
+        if !output.status.success() {
+            return Err(StorageError::Command(
+                "git",
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ));
+        }
+        let git_dir = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(Self {
+            output_dir: PathBuf::from(output_dir),
+            link_prefix: link_prefix.to_string(),
+            lfs_dir: Path::new(&git_dir).join("lfs").join("objects"),
+        })
+    }
+
+    fn link(&self, file_name: &str) -> String {
+        join_link(&self.link_prefix, file_name)
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for GitLfsStorage {
+    async fn save(&self, file_name: &str, content: Bytes) -> Result<String, StorageError> {
+        tracing::debug!("saving {} to git-lfs", file_name);
+        // object layout follows https://github.com/git-lfs/git-lfs/blob/main/docs/spec.md
+        let oid = hex::encode(Sha256::digest(&content));
+        let object_dir = self.lfs_dir.join(&oid[0..2]).join(&oid[2..4]);
+        std::fs::create_dir_all(&object_dir)?;
+        std::fs::write(object_dir.join(&oid), &content)?;
+
+        let pointer = format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize {}\n",
+            oid,
+            content.len()
+        );
+        let path = self.output_dir.join(file_name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, pointer)?;
+        Ok(self.link(file_name))
+    }
+
+    async fn lookup(&self, file_name: &str) -> Result<Option<String>, StorageError> {
+        if self.output_dir.join(file_name).is_file() {
+            return Ok(Some(self.link(file_name)));
+        }
+        Ok(None)
+    }
+}
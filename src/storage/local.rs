@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use super::{join_link, DedupStrategy, Storage, StorageError};
+use crate::{
+    checksums::hash_file,
+    utils::{list_files, relative_link},
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::symlink;
+#[cfg(windows)]
+use std::os::windows::fs::symlink_file as symlink;
+
+/// Save images to local output dir.
+pub struct LocalStorage {
+    output_dir: PathBuf,
+    link_prefix: String,
+    dedup: DedupStrategy,
+    /// Other directories whose files are linked to as well, like other page bundles.
+    dedup_dirs: Vec<PathBuf>,
+    /// Built from the directories before the first save unless copying.
+    index: Mutex<Option<Index>>,
+}
+
+/// Saved files by content hash, and symlinks by the file they point to.
+#[derive(Default)]
+struct Index {
+    files: HashMap<String, PathBuf>,
+    symlinks: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl Index {
+    /// Hash files in the directories, the first one found of the same content is kept.
+    fn build(dirs: &[PathBuf]) -> std::io::Result<Self> {
+        let mut index = Self::default();
+        for dir in dirs {
+            let mut names = Vec::new();
+            list_files(dir, "", &mut names)?;
+            for name in names {
+                let path = dir.join(&name);
+                if path.symlink_metadata()?.file_type().is_symlink() {
+                    // broken ones point to nothing to keep
+                    if let Ok(target) = std::fs::canonicalize(&path) {
+                        index.symlinks.entry(target).or_default().push(path);
+                    }
+                    continue;
+                }
+                index.files.entry(hash_file(&path)?).or_insert(path);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Forget the path, and if symlinks point to it, move its content to the first of them
+    /// and point the others there, so they keep the content when the path is saved again.
+    fn detach(&mut self, path: &Path) -> std::io::Result<()> {
+        self.files.retain(|_, p| p != path);
+        for links in self.symlinks.values_mut() {
+            links.retain(|l| l != path);
+        }
+        let target = match std::fs::canonicalize(path) {
+            Ok(target) => target,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut links = match self.symlinks.remove(&target) {
+            Some(links) if !links.is_empty() => links,
+            _ => return Ok(()),
+        };
+        let first = links.remove(0);
+        std::fs::rename(path, &first)?;
+        for link in links.iter() {
+            std::fs::remove_file(link)?;
+            symlink(relative_link(parent(link), &first)?, link)?;
+        }
+        self.symlinks.insert(std::fs::canonicalize(&first)?, links);
+        tracing::debug!("{} is moved to {}", path.display(), first.display());
+        Ok(())
+    }
+
+    /// Save the content to path, linked to a saved file of the same content if any.
+    fn save(&mut self, dedup: DedupStrategy, path: &Path, content: &[u8]) -> std::io::Result<()> {
+        self.detach(path)?;
+        // never write through a link shared with other names
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        let hash = hex::encode(Sha256::digest(content));
+        if let Some(existing) = self.files.get(&hash) {
+            match link_to(dedup, existing, path) {
+                Ok(()) => {
+                    tracing::debug!("{} linked to {}", path.display(), existing.display());
+                    if dedup == DedupStrategy::Symlink {
+                        let target = std::fs::canonicalize(existing)?;
+                        self.symlinks.entry(target).or_default().push(path.into());
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "linking {} with error {}, copied instead",
+                        path.display(),
+                        e
+                    )
+                }
+            }
+        }
+        std::fs::write(path, content)?;
+        self.files.entry(hash).or_insert_with(|| path.into());
+        Ok(())
+    }
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn link_to(dedup: DedupStrategy, existing: &Path, path: &Path) -> std::io::Result<()> {
+    match dedup {
+        DedupStrategy::Copy => std::fs::copy(existing, path).map(|_| ()),
+        DedupStrategy::Hardlink => std::fs::hard_link(existing, path),
+        DedupStrategy::Symlink => symlink(relative_link(parent(path), existing)?, path),
+    }
+}
+
+impl LocalStorage {
+    /// You may make sure the output_dir already exists.
+    pub fn new(
+        output_dir: &str,
+        link_prefix: &str,
+        dedup: DedupStrategy,
+        dedup_dirs: &[String],
+    ) -> Self {
+        Self {
+            output_dir: PathBuf::from(output_dir),
+            link_prefix: link_prefix.to_string(),
+            dedup,
+            dedup_dirs: dedup_dirs.iter().map(PathBuf::from).collect(),
+            index: Mutex::new(None),
+        }
+    }
+
+    fn link(&self, file_name: &str) -> String {
+        join_link(&self.link_prefix, file_name)
+    }
+
+    /// Build the index if not yet, files in output dir are preferred to be linked to.
+    async fn ensure_index(&self, index: &mut Option<Index>) -> Result<(), StorageError> {
+        if index.is_none() {
+            let dirs: Vec<PathBuf> = std::iter::once(&self.output_dir)
+                .chain(self.dedup_dirs.iter())
+                .cloned()
+                .collect();
+            let built = tokio::task::spawn_blocking(move || Index::build(&dirs))
+                .await
+                .expect("indexing task panicked")?;
+            tracing::debug!("indexed {} saved files", built.files.len());
+            *index = Some(built);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for LocalStorage {
+    async fn prepare(&self) -> Result<(), StorageError> {
+        if self.dedup != DedupStrategy::Copy {
+            self.ensure_index(&mut *self.index.lock().await).await?;
+        }
+        Ok(())
+    }
+
+    async fn save(&self, file_name: &str, content: Bytes) -> Result<String, StorageError> {
+        tracing::debug!("saving {}", file_name);
+        let path = self.output_dir.join(file_name);
+        // file name may have sub directories
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if self.dedup == DedupStrategy::Copy {
+            // never write through a link shared with other names
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            tokio::fs::write(&path, &content).await?;
+            return Ok(self.link(file_name));
+        }
+
+        // held until saved, so the same content saved in parallel is linked as well
+        let mut guard = self.index.lock().await;
+        self.ensure_index(&mut guard).await?;
+        let mut index = guard.take().expect("index is built");
+        let dedup = self.dedup;
+        let (index, ret) = tokio::task::spawn_blocking(move || {
+            let ret = index.save(dedup, &path, &content);
+            (index, ret)
+        })
+        .await
+        .expect("saving task panicked");
+        *guard = Some(index);
+        ret?;
+        Ok(self.link(file_name))
+    }
+
+    async fn lookup(&self, file_name: &str) -> Result<Option<String>, StorageError> {
+        match tokio::fs::metadata(self.output_dir.join(file_name)).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(self.link(file_name))),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_dedup_symlink() {
+        use super::{DedupStrategy, LocalStorage, Storage};
+
+        let dir = std::env::temp_dir().join("image-downloader-dedup");
+        let _ = std::fs::remove_dir_all(&dir);
+        let (output, other) = (dir.join("post-b"), dir.join("post-a"));
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+        std::fs::write(other.join("logo.png"), "logo").unwrap();
+
+        let storage = LocalStorage::new(
+            &output.to_string_lossy(),
+            "",
+            DedupStrategy::Symlink,
+            &[other.to_string_lossy().into_owned()],
+        );
+        storage.prepare().await.unwrap();
+        storage.save("a.png", "logo".into()).await.unwrap();
+        storage.save("b.png", "logo".into()).await.unwrap();
+        let a = std::fs::symlink_metadata(output.join("a.png")).unwrap();
+        assert!(a.file_type().is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(output.join("a.png")).unwrap(),
+            "logo"
+        );
+
+        // names linked to the saved one keep its content
+        storage.save("c.png", "c".into()).await.unwrap();
+        storage.save("c2.png", "c".into()).await.unwrap();
+        storage.save("c3.png", "c".into()).await.unwrap();
+        storage.save("c.png", "new".into()).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output.join("c.png")).unwrap(),
+            "new"
+        );
+        assert_eq!(std::fs::read_to_string(output.join("c2.png")).unwrap(), "c");
+        assert_eq!(std::fs::read_to_string(output.join("c3.png")).unwrap(), "c");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
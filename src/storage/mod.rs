@@ -0,0 +1,159 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use reqwest::{Client, StatusCode};
+
+use crate::Opts;
+
+mod git_lfs;
+mod local;
+mod s3;
+mod sftp;
+mod webdav;
+
+pub use self::s3::{S3Client, S3Error};
+pub use git_lfs::GitLfsStorage;
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+pub use sftp::SftpStorage;
+pub use webdav::WebDavStorage;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("reqwest error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("invalid status code: {0}")]
+    InvalidStatusCode(StatusCode),
+    #[error("s3 error: {0}")]
+    S3(#[from] S3Error),
+    #[error("command {0} failed: {1}")]
+    Command(&'static str, String),
+    #[error("missing option: {0}")]
+    MissingOption(&'static str),
+}
+
+/// Where images are saved to.
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+    /// Save image content with the file name and return the link used in markdown.
+    async fn save(&self, file_name: &str, content: Bytes) -> Result<String, StorageError>;
+
+    /// Return the link if the file name is already saved, so downloading can be skipped.
+    /// Storages unable to tell always download.
+    async fn lookup(&self, _file_name: &str) -> Result<Option<String>, StorageError> {
+        Ok(None)
+    }
+
+    /// Called once before images are saved, for slow setup kept off the first save.
+    async fn prepare(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Local,
+    S3,
+    WebDav,
+    Sftp,
+    GitLfs,
+}
+
+/// How local storage saves an image whose content is already saved under another name.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupStrategy {
+    /// Write a full copy.
+    Copy,
+    Hardlink,
+    /// Symlink relative to the saved one.
+    Symlink,
+}
+
+/// Build storage of the given kind with options.
+pub fn build_storage(kind: StorageKind, opts: &Opts) -> Result<Arc<dyn Storage>, StorageError> {
+    let timeout = Duration::from_secs(opts.timeout_sec as u64);
+    let storage: Arc<dyn Storage> = match kind {
+        StorageKind::Local => Arc::new(LocalStorage::new(
+            &opts.output_dir,
+            &opts.link_prefix,
+            opts.dedup_strategy,
+            &opts.dedup_dir,
+        )),
+        StorageKind::S3 => {
+            let bucket = opts
+                .s3_bucket
+                .as_deref()
+                .ok_or(StorageError::MissingOption("s3-bucket"))?;
+            let endpoint = opts
+                .s3_endpoint
+                .as_deref()
+                .ok_or(StorageError::MissingOption("s3-endpoint"))?;
+            let public_url = opts
+                .s3_public_url
+                .as_deref()
+                .ok_or(StorageError::MissingOption("s3-public-url"))?;
+            Arc::new(S3Storage::new(
+                S3Client::from_env(endpoint, bucket, &opts.s3_region)?,
+                &opts.s3_prefix,
+                public_url,
+                timeout,
+            ))
+        }
+        StorageKind::WebDav => {
+            let endpoint = opts
+                .remote_endpoint
+                .as_deref()
+                .ok_or(StorageError::MissingOption("remote-endpoint"))?;
+            let public_url = opts.remote_prefix.as_deref().unwrap_or(endpoint);
+            Arc::new(WebDavStorage::from_env(endpoint, public_url, timeout))
+        }
+        StorageKind::Sftp => {
+            let endpoint = opts
+                .remote_endpoint
+                .as_deref()
+                .ok_or(StorageError::MissingOption("remote-endpoint"))?;
+            let public_url = opts
+                .remote_prefix
+                .as_deref()
+                .ok_or(StorageError::MissingOption("remote-prefix"))?;
+            Arc::new(SftpStorage::new(endpoint, public_url))
+        }
+        StorageKind::GitLfs => Arc::new(GitLfsStorage::new(&opts.output_dir, &opts.link_prefix)?),
+    };
+    Ok(storage)
+}
+
+/// Http client for storages uploading with http.
+fn http_client(timeout: Duration) -> Client {
+    Client::builder()
+        .timeout(timeout)
+        .build()
+        .expect("unable to build reqwest client")
+}
+
+fn join_url(base: &str, file_name: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), file_name)
+}
+
+/// Link of a local file under prefix. It is an url, so always `/` separated on any OS.
+fn join_link(prefix: &str, file_name: &str) -> String {
+    match prefix {
+        "" => file_name.to_string(),
+        prefix => join_url(prefix, file_name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_join_link() {
+        use super::join_link;
+
+        assert_eq!(join_link("/images", "a.png"), "/images/a.png");
+        assert_eq!(join_link("/images/", "2020/a.png"), "/images/2020/a.png");
+        assert_eq!(join_link("/", "a.png"), "/a.png");
+        assert_eq!(join_link("", "a.png"), "a.png");
+    }
+}
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+use super::{http_client, join_url, Storage, StorageError};
+use crate::utils::{ext_to_mime, get_path_ext};
+
+#[derive(Debug, thiserror::Error)]
+pub enum S3Error {
+    #[error("reqwest error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("invalid status code: {0}")]
+    InvalidStatusCode(StatusCode),
+    #[error("invalid endpoint: {0}")]
+    InvalidEndpoint(String),
+    #[error("missing credential env: {0}")]
+    MissingCredential(&'static str),
+}
+
+/// Minimal S3-compatible client which only puts objects with SigV4 and path style addressing.
+/// Works with AWS S3, Cloudflare R2, Aliyun OSS and MinIO.
+#[derive(Debug, Clone)]
+pub struct S3Client {
+    endpoint: Url,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Client {
+    /// Create client with credentials from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    pub fn from_env(endpoint: &str, bucket: &str, region: &str) -> Result<Self, S3Error> {
+        let endpoint =
+            Url::parse(endpoint).map_err(|_| S3Error::InvalidEndpoint(endpoint.to_string()))?;
+        if endpoint.host_str().is_none() {
+            return Err(S3Error::InvalidEndpoint(endpoint.to_string()));
+        }
+        let access_key = std::env::var("AWS_ACCESS_KEY_ID")
+            .map_err(|_| S3Error::MissingCredential("AWS_ACCESS_KEY_ID"))?;
+        let secret_key = std::env::var("AWS_SECRET_ACCESS_KEY")
+            .map_err(|_| S3Error::MissingCredential("AWS_SECRET_ACCESS_KEY"))?;
+        Ok(Self {
+            endpoint,
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key,
+            secret_key,
+        })
+    }
+
+    /// Put object to bucket with the given key.
+    pub async fn put_object(
+        &self,
+        client: &Client,
+        key: &str,
+        content: Bytes,
+        content_type: Option<&str>,
+    ) -> Result<(), S3Error> {
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let path = format!("/{}/{}", self.bucket, uri_encode(key));
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap(), port),
+            None => self.endpoint.host_str().unwrap().to_string(),
+        };
+        let payload_hash = hex::encode(Sha256::digest(&content));
+
+        // canonical request, see https://docs.aws.amazon.com/general/latest/gr/sigv4-create-canonical-request.html
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            path, host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes()),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let mut req = client
+            .put(url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(content);
+        if let Some(content_type) = content_type {
+            req = req.header("content-type", content_type);
+        }
+        let ret = req.send().await?;
+        if !ret.status().is_success() {
+            return Err(S3Error::InvalidStatusCode(ret.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Upload images to S3-compatible storage and link to the public bucket url.
+pub struct S3Storage {
+    client: S3Client,
+    http: Client,
+    key_prefix: String,
+    public_url: String,
+}
+
+impl S3Storage {
+    pub fn new(client: S3Client, key_prefix: &str, public_url: &str, timeout: Duration) -> Self {
+        Self {
+            client,
+            http: http_client(timeout),
+            key_prefix: key_prefix.trim_matches('/').to_string(),
+            public_url: public_url.to_string(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for S3Storage {
+    async fn save(&self, file_name: &str, content: Bytes) -> Result<String, StorageError> {
+        let key = match self.key_prefix.as_str() {
+            "" => file_name.to_string(),
+            key_prefix => format!("{}/{}", key_prefix, file_name),
+        };
+        tracing::debug!("uploading {} to s3", key);
+        let content_type = get_path_ext(&key).and_then(ext_to_mime);
+        self.client
+            .put_object(&self.http, &key, content, content_type)
+            .await?;
+        Ok(join_url(&self.public_url, &key))
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encode object key as S3 requires, `/` is kept.
+fn uri_encode(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for b in key.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_uri_encode() {
+        use super::uri_encode;
+        assert_eq!(uri_encode("images/abc.png"), "images/abc.png");
+        assert_eq!(uri_encode("a b+c.png"), "a%20b%2Bc.png");
+    }
+}
@@ -0,0 +1,74 @@
+use std::process::Stdio;
+
+use bytes::Bytes;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use super::{join_url, Storage, StorageError};
+
+/// Upload images with the system `ssh`, authentication is left to ssh config and agent.
+pub struct SftpStorage {
+    remote: String,
+    public_url: String,
+}
+
+impl SftpStorage {
+    /// Remote is in scp format, e.g. `user@host:/var/www/images`.
+    pub fn new(remote: &str, public_url: &str) -> Self {
+        Self {
+            remote: remote.to_string(),
+            public_url: public_url.to_string(),
+        }
+    }
+}
+
+/// Quote the argument for the remote shell.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[async_trait::async_trait]
+impl Storage for SftpStorage {
+    /// Content is piped to `cat` on the remote, so nothing is written locally.
+    async fn save(&self, file_name: &str, content: Bytes) -> Result<String, StorageError> {
+        let target = join_url(&self.remote, file_name);
+        tracing::debug!("uploading {}", target);
+        let (host, path) = target.split_once(':').ok_or_else(|| {
+            StorageError::Command("ssh", format!("invalid remote {}", self.remote))
+        })?;
+        let dir = path.rsplit_once('/').map_or(".", |(dir, _)| dir);
+        let script = format!(
+            "mkdir -p {} && cat > {}",
+            shell_quote(if dir.is_empty() { "/" } else { dir }),
+            shell_quote(path)
+        );
+        let mut child = Command::new("ssh")
+            .args(["-o", "BatchMode=yes", "--", host, &script])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let written = stdin.write_all(&content).await;
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(StorageError::Command(
+                "ssh",
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ));
+        }
+        written?;
+        Ok(join_url(&self.public_url, file_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_shell_quote() {
+        use super::shell_quote;
+
+        assert_eq!(shell_quote("/var/www/a b.png"), "'/var/www/a b.png'");
+        assert_eq!(shell_quote("it's.png"), r"'it'\''s.png'");
+    }
+}
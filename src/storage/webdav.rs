@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use reqwest::Client;
+
+use super::{http_client, join_url, Storage, StorageError};
+
+/// Upload images to a WebDAV server(or anything accepting HTTP PUT).
+pub struct WebDavStorage {
+    client: Client,
+    endpoint: String,
+    public_url: String,
+    credential: Option<(String, String)>,
+}
+
+impl WebDavStorage {
+    /// Create storage with optional basic auth from `WEBDAV_USERNAME` and `WEBDAV_PASSWORD`.
+    pub fn from_env(endpoint: &str, public_url: &str, timeout: Duration) -> Self {
+        let credential = std::env::var("WEBDAV_USERNAME")
+            .ok()
+            .map(|user| (user, std::env::var("WEBDAV_PASSWORD").unwrap_or_default()));
+        Self {
+            client: http_client(timeout),
+            endpoint: endpoint.to_string(),
+            public_url: public_url.to_string(),
+            credential,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for WebDavStorage {
+    async fn save(&self, file_name: &str, content: Bytes) -> Result<String, StorageError> {
+        let url = join_url(&self.endpoint, file_name);
+        tracing::debug!("uploading {}", url);
+        let mut req = self.client.put(&url).body(content);
+        if let Some((user, password)) = &self.credential {
+            req = req.basic_auth(user, Some(password));
+        }
+        let ret = req.send().await?;
+        if !ret.status().is_success() {
+            return Err(StorageError::InvalidStatusCode(ret.status()));
+        }
+        Ok(join_url(&self.public_url, file_name))
+    }
+}
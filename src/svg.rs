@@ -0,0 +1,132 @@
+use bytes::Bytes;
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader, Writer,
+};
+
+/// Elements removed with their children.
+const UNSAFE_ELEMENTS: &[&[u8]] = &[
+    b"script",
+    b"foreignObject",
+    b"iframe",
+    b"embed",
+    b"object",
+    b"handler",
+    b"listener",
+];
+
+/// Remove scripts, event handlers and external references from svg, since it is served
+/// from our own domain afterwards. Only fragment and inline image references are kept.
+pub fn sanitize_svg(content: &[u8]) -> Result<Bytes, quick_xml::Error> {
+    let mut reader = Reader::from_reader(content);
+    let mut writer = Writer::new(Vec::with_capacity(content.len()));
+    let mut buf = Vec::new();
+    // depth inside a removed element
+    let mut skipping = 0usize;
+    let mut in_style = false;
+    loop {
+        let event = reader.read_event_into(&mut buf)?;
+        match event {
+            Event::Eof => break,
+            Event::Start(_) if skipping > 0 => skipping += 1,
+            Event::End(_) if skipping > 0 => skipping -= 1,
+            _ if skipping > 0 => (),
+            Event::Start(e) if is_unsafe(&e) => skipping = 1,
+            Event::Empty(e) if is_unsafe(&e) => (),
+            Event::Start(e) => {
+                in_style = e.local_name().as_ref() == b"style";
+                writer.write_event(Event::Start(sanitize_element(&e)?))?
+            }
+            Event::End(e) => {
+                in_style = false;
+                writer.write_event(Event::End(e))?
+            }
+            // stylesheets loading external resources are emptied
+            Event::Text(ref t)
+                if in_style && has_external_url(&t.unescape()?.to_ascii_lowercase()) => {}
+            Event::CData(ref t)
+                if in_style
+                    && has_external_url(&String::from_utf8_lossy(t).to_ascii_lowercase()) => {}
+            Event::Empty(e) => writer.write_event(Event::Empty(sanitize_element(&e)?))?,
+            // processing instructions may load stylesheets
+            Event::PI(_) | Event::DocType(_) => (),
+            event => writer.write_event(event)?,
+        }
+        buf.clear();
+    }
+    Ok(writer.into_inner().into())
+}
+
+fn is_unsafe(e: &BytesStart) -> bool {
+    let name = e.local_name();
+    if UNSAFE_ELEMENTS.contains(&name.as_ref()) {
+        return true;
+    }
+    // animations may set href to javascript
+    if matches!(name.as_ref(), b"set" | b"animate") {
+        return e.attributes().flatten().any(|a| {
+            a.key.local_name().as_ref() == b"attributeName"
+                && a.value.as_ref().to_ascii_lowercase().ends_with(b"href")
+        });
+    }
+    false
+}
+
+/// Drop event handlers, external references and styles loading external resources.
+fn sanitize_element<'a>(e: &BytesStart<'a>) -> Result<BytesStart<'a>, quick_xml::Error> {
+    let mut sanitized = e.to_owned();
+    sanitized.clear_attributes();
+    for attr in e.attributes() {
+        let attr = attr?;
+        let key = attr.key.local_name();
+        let value = attr.unescape_value()?.trim().to_ascii_lowercase();
+        let keep = if key.as_ref().to_ascii_lowercase().starts_with(b"on") {
+            false
+        } else if key.as_ref() == b"href" || key.as_ref() == b"src" {
+            value.starts_with('#') || value.starts_with("data:image/")
+        } else {
+            !has_external_url(&value)
+        };
+        if keep {
+            sanitized.push_attribute(attr);
+        }
+    }
+    Ok(sanitized)
+}
+
+/// Whether css-like value references resources outside of the document.
+fn has_external_url(value: &str) -> bool {
+    if value.contains("@import") || value.contains("javascript:") {
+        return true;
+    }
+    value.split("url(").skip(1).any(|rest| {
+        let target = rest.trim_start_matches(['\'', '"', ' ']);
+        !(target.starts_with('#') || target.starts_with("data:image/"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_sanitize_svg() {
+        use super::sanitize_svg;
+
+        let svg = r##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" onload="alert(1)">
+<script>alert(2)</script>
+<foreignObject><div><p>hi</p></div></foreignObject>
+<a href="javascript:alert(3)"><rect fill="url(#g)" style="fill:url(https://evil.com/x)"/></a>
+<use xlink:href="https://evil.com/a.svg#b"/>
+<use href="#local"/>
+<set attributeName="href" to="javascript:alert(4)"/>
+<style>@import url(https://evil.com/a.css);</style>
+</svg>"##;
+        let sanitized = String::from_utf8(sanitize_svg(svg.as_bytes()).unwrap().to_vec()).unwrap();
+        for bad in ["alert", "evil.com", "foreignObject", "onload", "<set"] {
+            assert!(!sanitized.contains(bad), "{} in {}", bad, sanitized);
+        }
+        assert!(sanitized.contains(r##"<rect fill="url(#g)"/>"##));
+        assert!(sanitized.contains(r##"<use href="#local"/>"##));
+        assert!(sanitized.starts_with(r#"<?xml version="1.0"?>"#));
+    }
+}
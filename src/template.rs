@@ -0,0 +1,109 @@
+use std::path::Path;
+
+/// Split link prefix at the first segment with variables, return the static prefix and
+/// the template of sub directories resolved per markdown file.
+pub fn split_link_prefix(link_prefix: &str) -> (String, Option<String>) {
+    let var = match link_prefix.find('{') {
+        Some(var) => var,
+        None => return (link_prefix.to_string(), None),
+    };
+    let split = link_prefix[..var].rfind('/').map_or(0, |i| i + 1);
+    let prefix = link_prefix[..split].trim_end_matches('/');
+    (prefix.to_string(), Some(link_prefix[split..].to_string()))
+}
+
+/// Resolve the sub directory template for a markdown file under one of inputs.
+/// `{relpath}` is the directory of it relative to input, `{slug}` is the slug in front matter
+/// or the file stem, `{year}` is taken from date in front matter or a year-like directory.
+/// Empty and parent segments are dropped.
+pub fn resolve_subdir(template: &str, inputs: &[String], path: &Path, contents: &str) -> String {
+    let relative = inputs
+        .iter()
+        .find_map(|input| path.strip_prefix(input).ok())
+        .unwrap_or(path);
+    let relpath = relative
+        .parent()
+        .map(|p| p.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_default();
+    let slug = front_matter(contents, "slug").unwrap_or_else(|| {
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    });
+    let date = front_matter(contents, "date");
+    let year = date
+        .as_deref()
+        .and_then(|date| date.get(..4))
+        .into_iter()
+        .chain(relpath.split('/'))
+        .find(|s| s.len() == 4 && s.bytes().all(|b| b.is_ascii_digit()))
+        .unwrap_or_default()
+        .to_string();
+
+    let resolved = template
+        .replace("{relpath}", &relpath)
+        .replace("{slug}", &slug)
+        .replace("{year}", &year);
+    resolved
+        .split('/')
+        .filter(|s| !s.is_empty() && *s != "." && *s != "..")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Value of the key in yaml(`---`) or toml(`+++`) front matter.
+fn front_matter(contents: &str, key: &str) -> Option<String> {
+    let mut lines = contents.lines();
+    let fence = lines.next()?.trim_end();
+    if fence != "---" && fence != "+++" {
+        return None;
+    }
+    lines
+        .take_while(|line| line.trim_end() != fence)
+        .find_map(|line| {
+            let value = line.strip_prefix(key)?.trim_start();
+            let value = value
+                .strip_prefix(':')
+                .or_else(|| value.strip_prefix('='))?;
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            (!value.is_empty()).then(|| value.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_resolve_subdir() {
+        use super::{resolve_subdir, split_link_prefix};
+        use std::path::Path;
+
+        assert_eq!(split_link_prefix("/images"), ("/images".to_string(), None));
+        assert_eq!(
+            split_link_prefix("/images/{year}/{slug}"),
+            ("/images".to_string(), Some("{year}/{slug}".to_string()))
+        );
+        assert_eq!(
+            split_link_prefix("/images/a{slug}"),
+            ("/images".to_string(), Some("a{slug}".to_string()))
+        );
+
+        let inputs = ["content".to_string()];
+        let path = Path::new("content/posts/2020/foo.md");
+        assert_eq!(
+            resolve_subdir("{year}/{slug}", &inputs, path, "# foo"),
+            "2020/foo"
+        );
+        assert_eq!(
+            resolve_subdir("{relpath}", &inputs, path, "# foo"),
+            "posts/2020"
+        );
+        let contents = "---\ntitle: a\ndate: 2021-03-04\nslug: \"bar\"\n---\n# foo";
+        assert_eq!(
+            resolve_subdir("{year}/{slug}", &inputs, path, contents),
+            "2021/bar"
+        );
+        let path = Path::new("content/foo.md");
+        assert_eq!(resolve_subdir("{year}/{slug}", &inputs, path, ""), "foo");
+    }
+}
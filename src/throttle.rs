@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Bytes per second, parsed from strings like `10MB/s`, `512KiB` or `1000000`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bandwidth(pub u64);
+
+impl FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_suffix("/s").unwrap_or(s);
+        match s.parse() {
+            Ok(ByteSize(bytes)) => Ok(Self(bytes)),
+            Err(e) => Err(format!("invalid bandwidth {}: {}", s, e)),
+        }
+    }
+}
+
+/// Amount of bytes, parsed from strings like `2GB`, `512KiB` or `1000000`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: f64 = number.parse().map_err(|_| format!("invalid size {}", s))?;
+        let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1000,
+            "kib" => 1 << 10,
+            "m" | "mb" => 1000 * 1000,
+            "mib" => 1 << 20,
+            "g" | "gb" => 1000 * 1000 * 1000,
+            "gib" => 1 << 30,
+            _ => return Err(format!("invalid size unit {}", unit)),
+        };
+        let bytes = (number * multiplier as f64) as u64;
+        if bytes == 0 {
+            return Err("size must be positive".to_string());
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// Limit the rate of received bytes, shared by all downloads using it.
+pub struct Limiter {
+    rate: f64,
+    /// When the bytes consumed so far are all allowed.
+    next: Mutex<Instant>,
+}
+
+impl Limiter {
+    pub fn new(bandwidth: Bandwidth) -> Self {
+        Self {
+            rate: bandwidth.0 as f64,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserve time for the bytes and return when they are allowed.
+    fn reserve(&self, len: usize) -> Instant {
+        let mut next = self.next.lock().unwrap();
+        // idle time is not saved up for bursts
+        *next = (*next).max(Instant::now()) + Duration::from_secs_f64(len as f64 / self.rate);
+        *next
+    }
+}
+
+/// Global and per host bandwidth limits.
+pub struct Throttle {
+    global: Option<Limiter>,
+    per_host: Option<Bandwidth>,
+    hosts: Mutex<HashMap<String, Arc<Limiter>>>,
+}
+
+impl Throttle {
+    pub fn new(global: Option<Bandwidth>, per_host: Option<Bandwidth>) -> Self {
+        Self {
+            global: global.map(Limiter::new),
+            per_host,
+            hosts: Default::default(),
+        }
+    }
+
+    /// Wait until the bytes received from the host are allowed by both limits, and the
+    /// extra one of its profile if any.
+    pub async fn consume(&self, host: &str, len: usize, extra: Option<&Limiter>) {
+        let mut until = self.global.as_ref().map(|l| l.reserve(len));
+        until = until.max(extra.map(|l| l.reserve(len)));
+        if let Some(per_host) = self.per_host {
+            let limiter = self
+                .hosts
+                .lock()
+                .unwrap()
+                .entry(host.to_string())
+                .or_insert_with(|| Arc::new(Limiter::new(per_host)))
+                .clone();
+            until = until.max(Some(limiter.reserve(len)));
+        }
+        if let Some(until) = until {
+            tokio::time::sleep_until(until).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_bandwidth() {
+        use super::Bandwidth;
+
+        assert_eq!("10MB/s".parse(), Ok(Bandwidth(10_000_000)));
+        assert_eq!("512KiB".parse(), Ok(Bandwidth(512 * 1024)));
+        assert_eq!("1.5m".parse(), Ok(Bandwidth(1_500_000)));
+        assert_eq!("2000".parse(), Ok(Bandwidth(2000)));
+        assert!("10XB/s".parse::<Bandwidth>().is_err());
+        assert!("0".parse::<Bandwidth>().is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        use super::ByteSize;
+
+        assert_eq!("2GB".parse(), Ok(ByteSize(2_000_000_000)));
+        assert_eq!("512KiB".parse(), Ok(ByteSize(512 * 1024)));
+        assert!("10MB/s".parse::<ByteSize>().is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
+
+use futures::StreamExt;
+
+use crate::{
+    downloader::{find_markdown, rewrite_markdown, scan_markdown, ProcessError, RewriteOpts},
+    regexp::RegexWrapper,
+    shutdown::Shutdown,
+    storage::{build_storage, Storage},
+    utils::{is_contained, local_file_name},
+    Opts,
+};
+
+/// Process markdown, upload local images to remote storage and replace.
+pub async fn process_markdown_reverse(opts: Opts, shutdown: Shutdown) -> Result<(), ProcessError> {
+    let storage = build_storage(opts.storage, &opts)?;
+
+    // collect local links
+    let regex = RegexWrapper::with_prefix(&opts.link_prefix);
+    let file_list = find_markdown(&opts, opts.changed_since.as_deref())?;
+    let (sources, _) = scan_markdown(&file_list, &regex, opts.file_limit).await?;
+
+    // upload them
+    let rewrite_opts = RewriteOpts::from(&opts);
+    let result_mapping = upload_images(
+        sources.into_keys().collect(),
+        opts.output_dir,
+        opts.link_prefix,
+        storage,
+        opts.current_limit,
+        shutdown,
+    )
+    .await;
+    tracing::info!("uploaded {} images", result_mapping.len());
+
+    // replace them back
+    rewrite_markdown(&file_list, &regex, &result_mapping, &rewrite_opts)?;
+    Ok(())
+}
+
+/// Upload local images to storage and return the result of new url.
+async fn upload_images(
+    links: HashSet<String>,
+    output_dir: String,
+    prefix: String,
+    storage: Arc<dyn Storage>,
+    current_limit: u32,
+    mut shutdown: Shutdown,
+) -> HashMap<String, String> {
+    futures::stream::iter(links)
+        // stop issuing new uploads once interrupted
+        .take_until(async move { shutdown.triggered().await })
+        .map(|link| upload_image(link, &output_dir, &prefix, storage.as_ref()))
+        .buffer_unordered(current_limit as usize)
+        .filter_map(|ret| async move { ret })
+        .collect()
+        .await
+}
+
+/// Upload a single local image, and return the link with its new url.
+async fn upload_image(
+    link: String,
+    output_dir: &str,
+    prefix: &str,
+    storage: &dyn Storage,
+) -> Option<(String, String)> {
+    // 1. read local image
+    let file_name = match local_file_name(&link, prefix).filter(|n| is_contained(n)) {
+        Some(file_name) => file_name,
+        None => {
+            tracing::warn!("skipping {}, not a file under output dir", &link);
+            return None;
+        }
+    };
+    let content = match tokio::fs::read(Path::new(output_dir).join(file_name)).await {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("reading local image {} with error {}", &link, e);
+            return None;
+        }
+    };
+
+    // 2. upload image
+    match storage.save(file_name, content.into()).await {
+        Ok(remote) => Some((link, remote)),
+        Err(e) => {
+            tracing::error!("uploading single image {} with error {}", &link, e);
+            None
+        }
+    }
+}
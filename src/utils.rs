@@ -1,9 +1,393 @@
-/// Extract path extension with dot.
+use std::{
+    ffi::OsString,
+    io::Write,
+    path::{Component, Path},
+};
+
+use crate::asset::is_asset_ext;
+
+/// Extract path extension with dot from the last path segment, query and fragment are ignored.
 pub fn get_path_ext(url: &str) -> Option<&str> {
-    let dot_pos = url.rfind('.')?;
-    let suffix = &url[dot_pos..];
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    // host is not a path segment, like `https://a.com`
+    let path = match path.split_once("//") {
+        Some((scheme, rest)) if !scheme.contains('/') => rest.split_once('/')?.1,
+        _ => path,
+    };
+    let segment = &path[path.rfind('/').map(|p| p + 1).unwrap_or(0)..];
+    let dot_pos = segment.rfind('.')?;
+    let suffix = &segment[dot_pos..];
     if suffix.chars().skip(1).all(|c| c.is_alphanumeric()) {
         return Some(suffix);
     }
     None
-}
\ No newline at end of file
+}
+
+/// Path extension of the url if it is of a known image or asset type, others like `.php`
+/// are left to the content type.
+pub fn known_path_ext(url: &str) -> Option<&str> {
+    get_path_ext(url).filter(|ext| ext_to_mime(ext).is_some() || is_asset_ext(ext))
+}
+
+/// Split a `data:` URI into its mime type and base64 payload.
+/// Only base64 encoded images are supported.
+pub fn split_data_uri(uri: &str) -> Option<(&str, &str)> {
+    let rest = uri.strip_prefix("data:")?;
+    let (meta, payload) = rest.split_once(',')?;
+    let mime = meta.strip_suffix(";base64")?;
+    if !mime.starts_with("image/") {
+        return None;
+    }
+    Some((mime, payload))
+}
+
+/// Path extensions of known image types.
+pub const IMAGE_EXTS: &[&str] = &[
+    ".png", ".jpeg", ".gif", ".webp", ".svg", ".bmp", ".ico", ".avif",
+];
+
+/// Detect image mime type by content, None is returned if it is not an image.
+pub fn sniff_mime(content: &[u8]) -> Option<&'static str> {
+    let starts = |magic: &[u8]| content.starts_with(magic);
+    if starts(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if starts(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        Some("image/gif")
+    } else if starts(b"RIFF") && content.get(8..12) == Some(b"WEBP") {
+        Some("image/webp")
+    } else if starts(b"BM") {
+        Some("image/bmp")
+    } else if starts(&[0, 0, 1, 0]) {
+        Some("image/x-icon")
+    } else if content.get(4..8) == Some(b"ftyp")
+        && matches!(content.get(8..12), Some(b"avif" | b"avis"))
+    {
+        Some("image/avif")
+    } else {
+        // svg is text, look for the root element near the start, after xml declaration,
+        // comments and doctype
+        let head =
+            String::from_utf8_lossy(&content[..content.len().min(1024)]).to_ascii_lowercase();
+        let head = head.trim_start_matches('\u{feff}').trim_start();
+        let svg = head.starts_with('<') && !head.contains("<html") && head.contains("<svg");
+        svg.then_some("image/svg+xml")
+    }
+}
+
+/// Whether the image can be decoded for resizing and so on, judging by its extension.
+pub fn is_decodable(file_name: &str) -> bool {
+    matches!(
+        get_path_ext(file_name)
+            .map(|ext| ext.to_ascii_lowercase())
+            .as_deref(),
+        Some(".png" | ".jpg" | ".jpeg" | ".webp")
+    )
+}
+
+/// Map an image mime type to a path extension with dot.
+pub fn mime_to_ext(mime: &str) -> Option<&'static str> {
+    match mime {
+        "image/png" => Some(".png"),
+        "image/jpeg" | "image/jpg" => Some(".jpeg"),
+        "image/gif" => Some(".gif"),
+        "image/webp" => Some(".webp"),
+        "image/svg+xml" => Some(".svg"),
+        "image/bmp" => Some(".bmp"),
+        "image/x-icon" | "image/vnd.microsoft.icon" => Some(".ico"),
+        "image/avif" => Some(".avif"),
+        _ => None,
+    }
+}
+
+/// Map a path extension with dot to an image mime type.
+pub fn ext_to_mime(ext: &str) -> Option<&'static str> {
+    match ext.to_ascii_lowercase().as_str() {
+        ".png" => Some("image/png"),
+        ".jpg" | ".jpeg" => Some("image/jpeg"),
+        ".gif" => Some("image/gif"),
+        ".webp" => Some("image/webp"),
+        ".svg" => Some("image/svg+xml"),
+        ".bmp" => Some("image/bmp"),
+        ".ico" => Some("image/x-icon"),
+        ".avif" => Some("image/avif"),
+        _ => None,
+    }
+}
+
+/// Get file name in output dir from a local link under prefix.
+pub fn local_file_name<'a>(link: &'a str, prefix: &str) -> Option<&'a str> {
+    Some(link.strip_prefix(prefix)?.trim_start_matches('/'))
+}
+
+/// Whether the file name only has normal components, so it stays in the dir it is joined
+/// to. Names with `..`, root or prefix components are not.
+pub fn is_contained(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Shorten `data:` URIs for logging, other urls are returned as is.
+pub fn display_url(url: &str) -> &str {
+    const MAX_DATA_URI_DISPLAY: usize = 48;
+    if url.starts_with("data:") && url.len() > MAX_DATA_URI_DISPLAY {
+        return url.get(..MAX_DATA_URI_DISPLAY).unwrap_or(url);
+    }
+    url
+}
+
+/// Normalize an url by lowercasing scheme and host, removing default port and
+/// stripping params matching any of strip_params, `*` at the end matches any suffix.
+/// Urls unable to parse are returned as is.
+pub fn normalize_url(url: &str, strip_params: &[String]) -> String {
+    let mut parsed = match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.has_host() => parsed,
+        _ => return url.to_string(),
+    };
+    if parsed.query().is_some() && !strip_params.is_empty() {
+        let stripped = |key: &str| {
+            strip_params.iter().any(|p| match p.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => key == p,
+            })
+        };
+        let pairs: Vec<(String, String)> = parsed
+            .query_pairs()
+            .filter(|(k, _)| !stripped(k))
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+        if pairs.is_empty() {
+            parsed.set_query(None);
+        } else {
+            parsed.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+    }
+    parsed.into()
+}
+
+/// Decode the original image url wrapped by an image proxy, e.g. GitHub camo,
+/// `images.weserv.nl/?url=` and `wsrv.nl/?url=`.
+pub fn unwrap_proxy(url: &str) -> Option<String> {
+    let parsed = reqwest::Url::parse(url).ok()?;
+    match parsed.host_str()? {
+        // https://camo.githubusercontent.com/<digest>/<hex encoded url>
+        "camo.githubusercontent.com" => {
+            let encoded = parsed.path_segments()?.nth(1)?;
+            String::from_utf8(hex::decode(encoded).ok()?).ok()
+        }
+        "images.weserv.nl" | "wsrv.nl" => {
+            let (_, target) = parsed.query_pairs().find(|(k, _)| k == "url")?;
+            // scheme is optional and `ssl:` is the legacy form of https
+            if target.starts_with("http://") || target.starts_with("https://") {
+                Some(target.into_owned())
+            } else if let Some(rest) = target.strip_prefix("ssl:") {
+                Some(format!("https://{}", rest.trim_start_matches("//")))
+            } else {
+                Some(format!("http://{}", target.trim_start_matches("//")))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Link to the path relative to the directory, like `../images`, always `/` separated.
+/// Both are made absolute with `.` and `..` resolved lexically, empty if they are the same.
+pub fn relative_link(from_dir: &Path, to: &Path) -> std::io::Result<String> {
+    let components = |path: &Path| -> std::io::Result<Vec<OsString>> {
+        let mut parts = Vec::new();
+        for component in std::path::absolute(path)?.components() {
+            match component {
+                Component::ParentDir => {
+                    parts.pop();
+                }
+                Component::CurDir | Component::RootDir => (),
+                c => parts.push(c.as_os_str().to_os_string()),
+            }
+        }
+        Ok(parts)
+    };
+    let (from, to) = (components(from_dir)?, components(to)?);
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let parts: Vec<_> = std::iter::repeat_n("..".into(), from.len() - common)
+        .chain(to[common..].iter().map(|c| c.to_string_lossy()))
+        .collect();
+    Ok(parts.join("/"))
+}
+
+/// Write file by writing a temp file in the same dir and renaming it, so a crash never
+/// leaves it truncated. Permissions are kept, and so is mtime if preserve_mtime is set.
+pub fn write_atomic(path: &Path, contents: &[u8], preserve_mtime: bool) -> std::io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let metadata = std::fs::metadata(path).ok();
+    let ret = (|| {
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(contents)?;
+        if let Some(metadata) = &metadata {
+            f.set_permissions(metadata.permissions())?;
+            if preserve_mtime {
+                f.set_modified(metadata.modified()?)?;
+            }
+        }
+        f.sync_all()?;
+        std::fs::rename(&tmp, path)
+    })();
+    if ret.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    ret
+}
+
+/// List files under dir recursively as `/` separated names relative to it, images may be
+/// saved in sub directories by link prefix templates. Hidden files are not ours.
+pub fn list_files(dir: &Path, relative: &str, files: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let name = match relative {
+            "" => name,
+            relative => format!("{}/{}", relative, name),
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_files(&entry.path(), &name, files)?;
+        } else if file_type.is_file() || file_type.is_symlink() {
+            files.push(name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_get_path_ext() {
+        use super::get_path_ext;
+
+        assert_eq!(get_path_ext("https://a.com/b.png"), Some(".png"));
+        assert_eq!(get_path_ext("https://a.com/b.png?w=100#top"), Some(".png"));
+        assert_eq!(get_path_ext("https://a.com/image?id=123&fmt=png"), None);
+        assert_eq!(get_path_ext("https://a.com/image?name=b.png"), None);
+        assert_eq!(get_path_ext("https://a.com/b"), None);
+        assert_eq!(get_path_ext("https://a.com"), None);
+        assert_eq!(get_path_ext("//cdn.a.com?v=1.2"), None);
+        assert_eq!(get_path_ext("a/b.png"), Some(".png"));
+    }
+
+    #[test]
+    fn test_known_path_ext() {
+        use super::known_path_ext;
+
+        assert_eq!(known_path_ext("https://a.com/b.JPG"), Some(".JPG"));
+        assert_eq!(known_path_ext("https://a.com/c.mp4"), Some(".mp4"));
+        assert_eq!(known_path_ext("https://a.com/thumb.php?id=1"), None);
+        assert_eq!(known_path_ext("https://a.com/Image.aspx?w=100"), None);
+        assert_eq!(known_path_ext("https://a.com"), None);
+    }
+
+    #[test]
+    fn test_sniff_mime() {
+        use super::sniff_mime;
+
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n\0\0"), Some("image/png"));
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8L"), Some("image/webp"));
+        assert_eq!(
+            sniff_mime(b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+            Some("image/svg+xml")
+        );
+        assert_eq!(
+            sniff_mime(b"<!DOCTYPE html><html><body>no hotlink</body></html>"),
+            None
+        );
+        assert_eq!(sniff_mime(b"{\"error\": 403}"), None);
+    }
+
+    #[test]
+    fn test_unwrap_proxy() {
+        use super::unwrap_proxy;
+
+        assert_eq!(
+            unwrap_proxy("https://camo.githubusercontent.com/0123abcd/68747470733a2f2f612e636f6d2f622e706e67").as_deref(),
+            Some("https://a.com/b.png")
+        );
+        assert_eq!(
+            unwrap_proxy("https://images.weserv.nl/?url=https%3A%2F%2Fa.com%2Fb.png&w=300")
+                .as_deref(),
+            Some("https://a.com/b.png")
+        );
+        assert_eq!(
+            unwrap_proxy("https://wsrv.nl/?url=ssl:a.com/b.png").as_deref(),
+            Some("https://a.com/b.png")
+        );
+        assert_eq!(
+            unwrap_proxy("https://wsrv.nl/?url=a.com/b.png").as_deref(),
+            Some("http://a.com/b.png")
+        );
+        assert_eq!(unwrap_proxy("https://a.com/b.png"), None);
+    }
+
+    #[test]
+    fn test_relative_link() {
+        use super::relative_link;
+        use std::path::Path;
+
+        let link = |from, to| relative_link(Path::new(from), Path::new(to)).unwrap();
+        assert_eq!(
+            link("content/posts/2020", "public/images"),
+            "../../../public/images"
+        );
+        assert_eq!(link("content/./posts/..", "content/images"), "images");
+        assert_eq!(link("/a/b", "/a/b"), "");
+        assert_eq!(link("/a/b", "/a"), "..");
+    }
+
+    #[test]
+    fn test_is_contained() {
+        use super::is_contained;
+
+        assert!(is_contained("2020/a.png"));
+        assert!(!is_contained("../../home/u/.ssh/id_rsa"));
+        assert!(!is_contained("a/../../b.png"));
+        assert!(!is_contained("/etc/passwd"));
+        assert!(!is_contained(""));
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        use super::write_atomic;
+        use std::time::{Duration, SystemTime};
+
+        let dir = std::env::temp_dir().join("image-downloader-test-write-atomic");
+        let _ = std::fs::create_dir_all(&dir);
+        let path = dir.join("a.md");
+        std::fs::write(&path, "old").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        write_atomic(&path, b"new", true).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+        assert!(!dir.join(".a.md.tmp").exists());
+    }
+}
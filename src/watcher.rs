@@ -0,0 +1,83 @@
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{
+    downloader::{find_markdown, is_included, process_files, ProcessError},
+    shutdown::Shutdown,
+    storage::build_storage,
+    Opts,
+};
+
+/// Changes arriving within this window are processed together.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Process all markdown once, then keep processing changed markdown files.
+pub async fn watch_markdown(opts: Opts, mut shutdown: Shutdown) -> Result<(), ProcessError> {
+    let storage = build_storage(opts.storage, &opts)?;
+    let file_list = find_markdown(&opts, opts.changed_since.as_deref())?;
+    let report = process_files(&opts, storage.clone(), &file_list, shutdown.clone()).await?;
+    if let Some(format) = opts.report {
+        report.emit(format, opts.report_file.as_ref())?;
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |ret: notify::Result<notify::Event>| {
+        match ret {
+            Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("watching error: {}", e),
+        }
+    })?;
+    // events may carry absolute paths
+    let mut roots = Vec::with_capacity(opts.input.len());
+    for input in opts.input.iter() {
+        watcher.watch(input.as_ref(), RecursiveMode::Recursive)?;
+        roots.push(std::fs::canonicalize(input)?);
+    }
+    tracing::info!(
+        "watching {} for changed markdown files",
+        opts.input.join(", ")
+    );
+
+    loop {
+        let path = tokio::select! {
+            path = rx.recv() => match path {
+                Some(path) => path,
+                None => break,
+            },
+            _ = shutdown.triggered() => break,
+        };
+        let mut changed = HashSet::new();
+        changed.insert(path);
+        // wait for the editor to finish writing and collect the burst of events
+        while let Ok(Some(path)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+            changed.insert(path);
+        }
+        let file_list: Vec<PathBuf> = changed
+            .into_iter()
+            .filter(|p| {
+                let relative = roots.iter().find_map(|root| p.strip_prefix(root).ok());
+                p.is_file() && is_included(&opts, relative.unwrap_or(p))
+            })
+            .collect();
+        if file_list.is_empty() {
+            continue;
+        }
+        tracing::info!("{} markdown files changed", file_list.len());
+        match process_files(&opts, storage.clone(), &file_list, shutdown.clone()).await {
+            Ok(report) => {
+                if let Some(format) = opts.report {
+                    report.emit(format, opts.report_file.as_ref())?;
+                }
+            }
+            Err(e) => tracing::error!("process changed markdown in error: {}", e),
+        }
+    }
+    Ok(())
+}